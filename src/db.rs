@@ -7,14 +7,19 @@ pub struct ScyllaDb {
     pub get_unprocessed_concepts: PreparedStatement,
     pub mark_concept_processed: PreparedStatement,
     pub mark_concept_processed_by_owner: PreparedStatement,
+    /// Only prepared with `video.fast_preview`, which needs a `playable` column
+    pub mark_concept_playable: Option<PreparedStatement>,
+    pub mark_concept_playable_by_owner: Option<PreparedStatement>,
     pub delete_concept: PreparedStatement,
     pub delete_concept_by_owner: PreparedStatement,
     pub delete_unprocessed_concept: PreparedStatement,
     pub get_concept: PreparedStatement,
+    /// Only prepared with `video.encryption`, which needs a `content_keys` table
+    pub insert_content_key: Option<PreparedStatement>,
 }
 
 impl ScyllaDb {
-    pub async fn connect(nodes: &[String], keyspace: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+    pub async fn connect(nodes: &[String], keyspace: &str, fast_preview: bool, encryption: bool) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let session = scylla::client::session_builder::SessionBuilder::new()
             .known_nodes(nodes)
             .use_keyspace(keyspace, false)
@@ -25,20 +30,36 @@ impl ScyllaDb {
         let get_unprocessed_concepts = session.prepare("SELECT id, type FROM unprocessed_concepts WHERE partition = 0").await?;
         let mark_concept_processed = session.prepare("UPDATE media_concepts SET processed = true WHERE id = ?").await?;
         let mark_concept_processed_by_owner = session.prepare("UPDATE media_concepts_by_owner SET processed = true WHERE owner = ? AND id = ?").await?;
+        let (mark_concept_playable, mark_concept_playable_by_owner) = if fast_preview {
+            (
+                Some(session.prepare("UPDATE media_concepts SET playable = true WHERE id = ?").await?),
+                Some(session.prepare("UPDATE media_concepts_by_owner SET playable = true WHERE owner = ? AND id = ?").await?),
+            )
+        } else {
+            (None, None)
+        };
         let delete_concept = session.prepare("DELETE FROM media_concepts WHERE id = ?").await?;
         let delete_concept_by_owner = session.prepare("DELETE FROM media_concepts_by_owner WHERE owner = ? AND id = ?").await?;
         let delete_unprocessed_concept = session.prepare("DELETE FROM unprocessed_concepts WHERE partition = 0 AND id = ?").await?;
         let get_concept = session.prepare("SELECT id, name, owner, type, processed FROM media_concepts WHERE id = ?").await?;
+        let insert_content_key = if encryption {
+            Some(session.prepare("INSERT INTO content_keys (id, scheme, kid, key, iv) VALUES (?, ?, ?, ?, ?)").await?)
+        } else {
+            None
+        };
 
         Ok(ScyllaDb {
             session,
             get_unprocessed_concepts,
             mark_concept_processed,
             mark_concept_processed_by_owner,
+            mark_concept_playable,
+            mark_concept_playable_by_owner,
             delete_concept,
             delete_concept_by_owner,
             delete_unprocessed_concept,
             get_concept,
+            insert_content_key,
         })
     }
 }
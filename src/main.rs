@@ -6,11 +6,11 @@ use std::fs;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 use ffmpeg_next::{codec, format, media};
-use rand::Rng;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::json;
 mod db;
+mod manifest;
 
 use std::process::Command;
 use std::time::Duration;
@@ -47,7 +47,27 @@ struct FfprobeChapterTags {
 struct FfprobeStream {
     index: Option<u32>,
     codec_name: Option<String>,
+    channels: Option<u32>,
     tags: Option<FfprobeTags>,
+    disposition: Option<FfprobeDisposition>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+struct FfprobeDisposition {
+    #[serde(default)]
+    default: u8,
+    #[serde(default)]
+    forced: u8,
+    #[serde(default)]
+    comment: u8,
+    #[serde(default)]
+    visual_impaired: u8,
+    #[serde(default)]
+    hearing_impaired: u8,
+    #[serde(default)]
+    dub: u8,
+    #[serde(default)]
+    original: u8,
 }
 
 #[derive(Deserialize, Debug)]
@@ -72,6 +92,8 @@ struct Config {
     pdf: PdfConfig,
     #[serde(default = "default_translation_config")]
     translation: TranslationConfig,
+    #[serde(default = "default_loudness_config")]
+    loudness: LoudnessConfig,
     #[serde(default = "default_upload_path")]
     upload_path: String,
     #[serde(default = "default_source_path")]
@@ -241,6 +263,41 @@ fn default_translation_config() -> TranslationConfig {
     }
 }
 
+#[derive(Deserialize, Clone, Debug)]
+struct LoudnessConfig {
+    /// Measure EBU R128 loudness of every audio output (default: false).
+    #[serde(default)]
+    enabled: bool,
+    /// Apply two-pass loudnorm normalization. When false, loudness is only measured
+    /// and stored in metadata.json so players can apply it as gain (default: true).
+    #[serde(default = "default_loudness_normalize")]
+    normalize: bool,
+    /// Integrated loudness target in LUFS (default: -23.0, EBU R128).
+    #[serde(default = "default_loudness_target_lufs")]
+    target_lufs: f64,
+    /// Maximum true peak in dBTP (default: -1.0).
+    #[serde(default = "default_loudness_true_peak")]
+    true_peak: f64,
+    /// Loudness range target in LU (default: 11.0).
+    #[serde(default = "default_loudness_range")]
+    loudness_range: f64,
+}
+
+fn default_loudness_normalize() -> bool { true }
+fn default_loudness_target_lufs() -> f64 { -23.0 }
+fn default_loudness_true_peak() -> f64 { -1.0 }
+fn default_loudness_range() -> f64 { 11.0 }
+
+fn default_loudness_config() -> LoudnessConfig {
+    LoudnessConfig {
+        enabled: false,
+        normalize: default_loudness_normalize(),
+        target_lufs: default_loudness_target_lufs(),
+        true_peak: default_loudness_true_peak(),
+        loudness_range: default_loudness_range(),
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 struct AudioTranscodeConfig {
     #[serde(default = "default_audio_codec")]
@@ -269,6 +326,17 @@ fn default_audio_lossless_codecs() -> Vec<String> {
     vec!["flac".to_string(), "wav".to_string(), "pcm_s16le".to_string()]
 }
 
+impl AudioTranscodeConfig {
+    /// `-vbr` and `-application` are libopus options; other encoders (e.g. aac for m4a) reject them.
+    fn opus_args(&self) -> String {
+        if self.codec == "libopus" {
+            format!(" -vbr {} -application {}", self.vbr, self.application)
+        } else {
+            String::new()
+        }
+    }
+}
+
 fn default_audio_transcode_config() -> AudioTranscodeConfig {
     AudioTranscodeConfig {
         codec: default_audio_codec(),
@@ -362,15 +430,41 @@ struct ThumbnailConfig {
     width: u32,
     #[serde(default = "default_thumbnail_height")]
     height: u32,
+    /// Score sampled frames (brightness, contrast, blur) to pick the poster instead of
+    /// using the middle of the video (default: true)
+    #[serde(default = "default_thumbnail_smart_selection")]
+    smart_selection: bool,
+    /// Number of evenly spaced windows scored for the poster frame (default: 12)
+    #[serde(default = "default_thumbnail_samples")]
+    samples: u32,
+    /// Frames per window fed to ffmpeg's `thumbnail` filter (default: 50)
+    #[serde(default = "default_thumbnail_frames_per_sample")]
+    frames_per_sample: u32,
+    /// Percentage of the duration skipped at the start and at the end (default: 5.0)
+    #[serde(default = "default_thumbnail_edge_skip_percent")]
+    edge_skip_percent: f64,
+    /// Number of ranked candidates saved to `thumbnails/` for the uploader (default: 5)
+    #[serde(default = "default_thumbnail_candidates")]
+    candidates: u32,
 }
 
 fn default_thumbnail_width() -> u32 { 1280 }
 fn default_thumbnail_height() -> u32 { 720 }
+fn default_thumbnail_smart_selection() -> bool { true }
+fn default_thumbnail_samples() -> u32 { 12 }
+fn default_thumbnail_frames_per_sample() -> u32 { 50 }
+fn default_thumbnail_edge_skip_percent() -> f64 { 5.0 }
+fn default_thumbnail_candidates() -> u32 { 5 }
 
 fn default_thumbnail_config() -> ThumbnailConfig {
     ThumbnailConfig {
         width: default_thumbnail_width(),
         height: default_thumbnail_height(),
+        smart_selection: default_thumbnail_smart_selection(),
+        samples: default_thumbnail_samples(),
+        frames_per_sample: default_thumbnail_frames_per_sample(),
+        edge_skip_percent: default_thumbnail_edge_skip_percent(),
+        candidates: default_thumbnail_candidates(),
     }
 }
 
@@ -412,6 +506,461 @@ fn default_preview_sprite_config() -> PreviewSpriteConfig {
     }
 }
 
+#[derive(Deserialize, Clone, Debug)]
+struct CropDetectConfig {
+    /// Run a sampled cropdetect pass and remove black bars before scaling (default: false).
+    #[serde(default)]
+    enabled: bool,
+    /// Number of sample windows spread across the video (default: 8).
+    #[serde(default = "default_crop_detect_samples")]
+    samples: u32,
+    /// Length of each sample window in seconds (default: 2.0).
+    #[serde(default = "default_crop_detect_sample_duration")]
+    sample_duration: f64,
+    /// Black threshold as a fraction of the pixel range, so it works for 8-bit
+    /// and 10-bit sources alike (default: 0.094, i.e. 24 of 255).
+    #[serde(default = "default_crop_detect_limit")]
+    limit: f64,
+    /// Crop width and height are rounded to a multiple of this value (default: 2).
+    #[serde(default = "default_crop_detect_round")]
+    round: u32,
+    /// Fraction of samples that must report the same crop before it is applied (default: 0.6).
+    #[serde(default = "default_crop_detect_min_agreement")]
+    min_agreement: f64,
+}
+
+fn default_crop_detect_samples() -> u32 { 8 }
+fn default_crop_detect_sample_duration() -> f64 { 2.0 }
+fn default_crop_detect_limit() -> f64 { 0.094 }
+fn default_crop_detect_round() -> u32 { 2 }
+fn default_crop_detect_min_agreement() -> f64 { 0.6 }
+
+fn default_crop_detect_config() -> CropDetectConfig {
+    CropDetectConfig {
+        enabled: false,
+        samples: default_crop_detect_samples(),
+        sample_duration: default_crop_detect_sample_duration(),
+        limit: default_crop_detect_limit(),
+        round: default_crop_detect_round(),
+        min_agreement: default_crop_detect_min_agreement(),
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct HoverPreviewConfig {
+    /// Generate the short looping hover preview (default: false).
+    #[serde(default)]
+    enabled: bool,
+    /// Number of excerpts stitched together (default: 5).
+    #[serde(default = "default_hover_preview_segments")]
+    segments: u32,
+    /// Length of each excerpt in seconds (default: 1.2).
+    #[serde(default = "default_hover_preview_segment_duration")]
+    segment_duration: f64,
+    /// Maximum preview width (default: 480).
+    #[serde(default = "default_hover_preview_width")]
+    width: u32,
+    /// Maximum preview height (default: 270).
+    #[serde(default = "default_hover_preview_height")]
+    height: u32,
+    /// Output frame rate (default: 12).
+    #[serde(default = "default_hover_preview_fps")]
+    fps: u32,
+    /// CRF for the MP4 (libx264) version (default: 30).
+    #[serde(default = "default_hover_preview_mp4_crf")]
+    mp4_crf: u32,
+    /// CRF for the animated AVIF version (default: 40).
+    #[serde(default = "default_hover_preview_avif_crf")]
+    avif_crf: u32,
+    /// Quality (0-100) for the animated WebP version (default: 60).
+    #[serde(default = "default_hover_preview_webp_quality")]
+    webp_quality: u32,
+}
+
+fn default_hover_preview_segments() -> u32 { 5 }
+fn default_hover_preview_segment_duration() -> f64 { 1.2 }
+fn default_hover_preview_width() -> u32 { 480 }
+fn default_hover_preview_height() -> u32 { 270 }
+fn default_hover_preview_fps() -> u32 { 12 }
+fn default_hover_preview_mp4_crf() -> u32 { 30 }
+fn default_hover_preview_avif_crf() -> u32 { 40 }
+fn default_hover_preview_webp_quality() -> u32 { 60 }
+
+fn default_hover_preview_config() -> HoverPreviewConfig {
+    HoverPreviewConfig {
+        enabled: false,
+        segments: default_hover_preview_segments(),
+        segment_duration: default_hover_preview_segment_duration(),
+        width: default_hover_preview_width(),
+        height: default_hover_preview_height(),
+        fps: default_hover_preview_fps(),
+        mp4_crf: default_hover_preview_mp4_crf(),
+        avif_crf: default_hover_preview_avif_crf(),
+        webp_quality: default_hover_preview_webp_quality(),
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct AutoChaptersConfig {
+    /// Build chapters from scene changes and silence when the source has none (default: false).
+    #[serde(default)]
+    enabled: bool,
+    /// Scene-change score (0.0-1.0) above which a cut counts as a boundary candidate (default: 0.4).
+    #[serde(default = "default_auto_chapters_scene_threshold")]
+    scene_threshold: f64,
+    /// Audio level in dB below which audio counts as silence (default: -35.0).
+    #[serde(default = "default_auto_chapters_silence_noise_db")]
+    silence_noise_db: f64,
+    /// Minimum silence length in seconds to count as a gap (default: 1.0).
+    #[serde(default = "default_auto_chapters_silence_min_duration")]
+    silence_min_duration: f64,
+    /// Minimum chapter length in seconds (default: 60.0).
+    #[serde(default = "default_auto_chapters_min_chapter_seconds")]
+    min_chapter_seconds: f64,
+    /// Maximum chapter length in seconds; longer stretches are split even without a cut (default: 600.0).
+    #[serde(default = "default_auto_chapters_max_chapter_seconds")]
+    max_chapter_seconds: f64,
+    /// Chapter title prefix, followed by the chapter number (default: "Chapter").
+    #[serde(default = "default_auto_chapters_title_prefix")]
+    title_prefix: String,
+    /// Width of the per-chapter thumbnails (default: 320).
+    #[serde(default = "default_auto_chapters_thumb_width")]
+    thumb_width: u32,
+    /// Height of the per-chapter thumbnails (default: 180).
+    #[serde(default = "default_auto_chapters_thumb_height")]
+    thumb_height: u32,
+}
+
+fn default_auto_chapters_scene_threshold() -> f64 { 0.4 }
+fn default_auto_chapters_silence_noise_db() -> f64 { -35.0 }
+fn default_auto_chapters_silence_min_duration() -> f64 { 1.0 }
+fn default_auto_chapters_min_chapter_seconds() -> f64 { 60.0 }
+fn default_auto_chapters_max_chapter_seconds() -> f64 { 600.0 }
+fn default_auto_chapters_title_prefix() -> String { "Chapter".to_string() }
+fn default_auto_chapters_thumb_width() -> u32 { 320 }
+fn default_auto_chapters_thumb_height() -> u32 { 180 }
+
+fn default_auto_chapters_config() -> AutoChaptersConfig {
+    AutoChaptersConfig {
+        enabled: false,
+        scene_threshold: default_auto_chapters_scene_threshold(),
+        silence_noise_db: default_auto_chapters_silence_noise_db(),
+        silence_min_duration: default_auto_chapters_silence_min_duration(),
+        min_chapter_seconds: default_auto_chapters_min_chapter_seconds(),
+        max_chapter_seconds: default_auto_chapters_max_chapter_seconds(),
+        title_prefix: default_auto_chapters_title_prefix(),
+        thumb_width: default_auto_chapters_thumb_width(),
+        thumb_height: default_auto_chapters_thumb_height(),
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct ChunkedEncodingConfig {
+    /// Split long inputs into chunks encoded in parallel (default: false).
+    #[serde(default)]
+    enabled: bool,
+    /// Only inputs at least this long (seconds) are chunked (default: 1800.0).
+    #[serde(default = "default_chunked_min_duration")]
+    min_duration: f64,
+    /// Target chunk length in seconds; boundaries snap to source keyframes (default: 300.0).
+    #[serde(default = "default_chunked_chunk_duration")]
+    chunk_duration: f64,
+    /// Maximum number of chunk encodes running at once across all renditions (default: 4).
+    #[serde(default = "default_chunked_parallel_limit")]
+    parallel_limit: u32,
+}
+
+fn default_chunked_min_duration() -> f64 { 1800.0 }
+fn default_chunked_chunk_duration() -> f64 { 300.0 }
+fn default_chunked_parallel_limit() -> u32 { 4 }
+
+fn default_chunked_encoding_config() -> ChunkedEncodingConfig {
+    ChunkedEncodingConfig {
+        enabled: false,
+        min_duration: default_chunked_min_duration(),
+        chunk_duration: default_chunked_chunk_duration(),
+        parallel_limit: default_chunked_parallel_limit(),
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct RemuxConfig {
+    /// Stream-copy the top rung when the source already matches the target codec (default: false).
+    #[serde(default)]
+    enabled: bool,
+    /// Highest source video bitrate in kbps that is still copied when the encoder has no
+    /// `max_bitrate_kbps` of its own (default: 12000).
+    #[serde(default = "default_remux_max_bitrate_kbps")]
+    max_bitrate_kbps: u32,
+    /// Source profiles that may be copied, as reported by ffprobe (default: ["Main", "High"]).
+    #[serde(default = "default_remux_allowed_profiles")]
+    allowed_profiles: Vec<String>,
+    /// Source pixel formats that may be copied (default: ["yuv420p", "yuv420p10le"]).
+    #[serde(default = "default_remux_allowed_pix_fmts")]
+    allowed_pix_fmts: Vec<String>,
+}
+
+fn default_remux_max_bitrate_kbps() -> u32 { 12000 }
+fn default_remux_allowed_profiles() -> Vec<String> { vec!["Main".to_string(), "High".to_string()] }
+fn default_remux_allowed_pix_fmts() -> Vec<String> { vec!["yuv420p".to_string(), "yuv420p10le".to_string()] }
+
+fn default_remux_config() -> RemuxConfig {
+    RemuxConfig {
+        enabled: false,
+        max_bitrate_kbps: default_remux_max_bitrate_kbps(),
+        allowed_profiles: default_remux_allowed_profiles(),
+        allowed_pix_fmts: default_remux_allowed_pix_fmts(),
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct FastPreviewConfig {
+    /// Publish the lowest rung, manifests and a thumbnail before the full ladder (default: false).
+    #[serde(default)]
+    enabled: bool,
+}
+
+fn default_fast_preview_config() -> FastPreviewConfig {
+    FastPreviewConfig { enabled: false }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum RenditionRequirement {
+    /// At least one rung (previous behaviour)
+    Any,
+    /// Every rung of the ladder
+    All,
+    /// The highest rung
+    Top,
+    /// At least `min_renditions` rungs
+    Count,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct RenditionPolicyConfig {
+    /// Which rungs must be encoded for the video to be published (default: "any").
+    #[serde(default = "default_rendition_policy_require")]
+    require: RenditionRequirement,
+    /// Minimum number of rungs when `require` is "count" (default: 1).
+    #[serde(default = "default_rendition_policy_min_renditions")]
+    min_renditions: u32,
+    /// Extra attempts for a failed encode job before the rung is given up (default: 1).
+    #[serde(default = "default_rendition_policy_retries")]
+    retries: u32,
+}
+
+fn default_rendition_policy_require() -> RenditionRequirement { RenditionRequirement::Any }
+fn default_rendition_policy_min_renditions() -> u32 { 1 }
+fn default_rendition_policy_retries() -> u32 { 1 }
+
+fn default_rendition_policy_config() -> RenditionPolicyConfig {
+    RenditionPolicyConfig {
+        require: default_rendition_policy_require(),
+        min_renditions: default_rendition_policy_min_renditions(),
+        retries: default_rendition_policy_retries(),
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum EncryptionScheme {
+    /// AES-CTR full-sample encryption, done by ffmpeg's mp4 muxer
+    Cenc,
+    /// AES-CBC pattern encryption, done with Bento4 `mp4encrypt` after packaging
+    Cbcs,
+}
+
+impl EncryptionScheme {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EncryptionScheme::Cenc => "cenc",
+            EncryptionScheme::Cbcs => "cbcs",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct EncryptionConfig {
+    /// Encrypt the DASH/HLS segments with a per-concept content key (default: false).
+    #[serde(default)]
+    enabled: bool,
+    /// Protection scheme, "cenc" or "cbcs" (default: "cenc").
+    #[serde(default = "default_encryption_scheme")]
+    scheme: EncryptionScheme,
+    /// Directory for the per-concept key files; must not be served publicly (default: "keys").
+    #[serde(default = "default_encryption_key_path")]
+    key_path: String,
+    /// ClearKey license server URL written to the MPD, `{id}` is the concept id (default: "").
+    #[serde(default)]
+    license_url: String,
+    /// URL serving the raw 16-byte key for HLS `EXT-X-KEY`, `{id}` is the concept id (default: "").
+    #[serde(default)]
+    key_url: String,
+    /// Bento4 `mp4encrypt` binary used for the cbcs scheme (default: "mp4encrypt").
+    #[serde(default = "default_encryption_mp4encrypt_path")]
+    mp4encrypt_path: String,
+}
+
+fn default_encryption_scheme() -> EncryptionScheme { EncryptionScheme::Cenc }
+fn default_encryption_key_path() -> String { "keys".to_string() }
+fn default_encryption_mp4encrypt_path() -> String { "mp4encrypt".to_string() }
+
+fn default_encryption_config() -> EncryptionConfig {
+    EncryptionConfig {
+        enabled: false,
+        scheme: default_encryption_scheme(),
+        key_path: default_encryption_key_path(),
+        license_url: String::new(),
+        key_url: String::new(),
+        mp4encrypt_path: default_encryption_mp4encrypt_path(),
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct WatermarkConfig {
+    /// Overlay a logo on every rendition (default: false).
+    #[serde(default)]
+    enabled: bool,
+    /// Logo image, ideally a PNG with alpha (default: "").
+    #[serde(default)]
+    image: String,
+    /// Corner (or center) the logo is placed in (default: "bottom_right").
+    #[serde(default = "default_watermark_position")]
+    position: WatermarkPosition,
+    /// Distance from the edges as a fraction of the video width (default: 0.02).
+    #[serde(default = "default_watermark_margin")]
+    margin: f32,
+    /// Logo width as a fraction of the video width (default: 0.1).
+    #[serde(default = "default_watermark_scale")]
+    scale: f32,
+    /// Logo opacity from 0.0 to 1.0 (default: 0.8).
+    #[serde(default = "default_watermark_opacity")]
+    opacity: f32,
+    /// Also overlay the logo on thumbnails and poster candidates (default: false).
+    #[serde(default)]
+    thumbnails: bool,
+    /// Also overlay the logo on preview sprites and the hover preview (default: false).
+    #[serde(default)]
+    sprites: bool,
+    /// Per-owner overrides, keyed by owner id (default: {}).
+    #[serde(default)]
+    owners: std::collections::HashMap<String, WatermarkOverride>,
+}
+
+/// Fields an owner can override; unset fields keep the global value.
+#[derive(Deserialize, Clone, Debug, Default)]
+struct WatermarkOverride {
+    enabled: Option<bool>,
+    image: Option<String>,
+    position: Option<WatermarkPosition>,
+    margin: Option<f32>,
+    scale: Option<f32>,
+    opacity: Option<f32>,
+}
+
+fn default_watermark_position() -> WatermarkPosition { WatermarkPosition::BottomRight }
+fn default_watermark_margin() -> f32 { 0.02 }
+fn default_watermark_scale() -> f32 { 0.1 }
+fn default_watermark_opacity() -> f32 { 0.8 }
+
+fn default_watermark_config() -> WatermarkConfig {
+    WatermarkConfig {
+        enabled: false,
+        image: String::new(),
+        position: default_watermark_position(),
+        margin: default_watermark_margin(),
+        scale: default_watermark_scale(),
+        opacity: default_watermark_opacity(),
+        thumbnails: false,
+        sprites: false,
+        owners: std::collections::HashMap::new(),
+    }
+}
+
+impl WatermarkConfig {
+    /// The watermark settings for a concept of `owner`, with the owner's override applied.
+    fn for_owner(&self, owner: Option<&str>) -> WatermarkConfig {
+        let mut resolved = self.clone();
+        if let Some(o) = owner.and_then(|owner| self.owners.get(owner)) {
+            resolved.enabled = o.enabled.unwrap_or(resolved.enabled);
+            resolved.image = o.image.clone().unwrap_or(resolved.image);
+            resolved.position = o.position.unwrap_or(resolved.position);
+            resolved.margin = o.margin.unwrap_or(resolved.margin);
+            resolved.scale = o.scale.unwrap_or(resolved.scale);
+            resolved.opacity = o.opacity.unwrap_or(resolved.opacity);
+        }
+        resolved
+    }
+
+    /// Whether a logo is actually overlaid: enabled with an existing image.
+    fn active(&self) -> bool {
+        self.enabled && !self.image.is_empty() && std::path::Path::new(&self.image).exists()
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DownloadMode {
+    /// Re-encode to H.264/AAC, playable nearly everywhere
+    H264,
+    /// Stream-copy the top rung and its audio as packaged for DASH
+    Remux,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct DownloadConfig {
+    /// Write a progressive `download.mp4` next to `video/` (default: false).
+    #[serde(default)]
+    enabled: bool,
+    /// "h264" for an H.264/AAC encode or "remux" for the top rendition as packaged (default: "h264").
+    #[serde(default = "default_download_mode")]
+    mode: DownloadMode,
+    /// Height cap of the H.264 encode; smaller sources keep their size (default: 1080).
+    #[serde(default = "default_download_max_height")]
+    max_height: u32,
+    /// libx264 CRF (default: 20).
+    #[serde(default = "default_download_crf")]
+    crf: u32,
+    /// libx264 preset (default: "medium").
+    #[serde(default = "default_download_preset")]
+    preset: String,
+    /// AAC bitrate per audio track in kbps for the H.264 encode (default: 192).
+    #[serde(default = "default_download_audio_bitrate")]
+    audio_bitrate: u32,
+    /// Embed the published captions as mov_text soft subtitles (default: false).
+    #[serde(default)]
+    subtitles: bool,
+}
+
+fn default_download_mode() -> DownloadMode { DownloadMode::H264 }
+fn default_download_max_height() -> u32 { 1080 }
+fn default_download_crf() -> u32 { 20 }
+fn default_download_preset() -> String { "medium".to_string() }
+fn default_download_audio_bitrate() -> u32 { 192 }
+
+fn default_download_config() -> DownloadConfig {
+    DownloadConfig {
+        enabled: false,
+        mode: default_download_mode(),
+        max_height: default_download_max_height(),
+        crf: default_download_crf(),
+        preset: default_download_preset(),
+        audio_bitrate: default_download_audio_bitrate(),
+        subtitles: false,
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 struct DashConfig {
     #[serde(default = "default_dash_audio_codec")]
@@ -422,12 +971,57 @@ struct DashConfig {
     audio_channels: u32,
     #[serde(default = "default_dash_segment_duration")]
     segment_duration: u32,
+    /// Segment duration in milliseconds; overrides `segment_duration` when set (default: unset).
+    #[serde(default)]
+    segment_duration_ms: Option<u32>,
+    /// Keep a multichannel rendition next to the downmix for sources with more
+    /// than `audio_channels` channels (default: false).
+    #[serde(default)]
+    preserve_surround: bool,
+    /// Codec for the surround rendition: "libopus" (multichannel), "aac" or "eac3" (default: "libopus").
+    #[serde(default = "default_dash_surround_codec")]
+    surround_codec: String,
+    /// Surround bitrate in kbps per channel, e.g. 64 gives 384k for 5.1 (default: 64).
+    #[serde(default = "default_dash_surround_bitrate_per_channel")]
+    surround_bitrate_per_channel: u32,
+    /// Also force keyframes at container chapter starts (default: false).
+    #[serde(default)]
+    force_chapter_keyframes: bool,
+    /// Check the packaged MPD/HLS output before publishing it (default: true).
+    #[serde(default = "default_dash_validate_output")]
+    validate_output: bool,
+    /// Allowed difference in seconds between a rendition's duration and the source (default: 2.0).
+    #[serde(default = "default_dash_duration_tolerance")]
+    duration_tolerance: f64,
+    /// Encode every audio track at one bitrate per quality step (base / `audio_bitrate_divisor`)
+    /// instead of a single bitrate (default: false).
+    #[serde(default)]
+    audio_ladder: bool,
+    /// Lowest bitrate in kbps an audio ladder rung may use (default: 48).
+    #[serde(default = "default_dash_audio_ladder_min_bitrate")]
+    audio_ladder_min_bitrate: u32,
+    /// Also encode a stereo AAC-LC track per audio stream and offer it in the HLS master
+    /// playlist for Safari/iOS; the MPD keeps `audio_codec` only (default: false).
+    #[serde(default)]
+    hls_aac: bool,
+    /// Languages to prefer for the default audio track, most preferred first (default: []).
+    #[serde(default)]
+    preferred_audio_languages: Vec<String>,
+    /// Publish captions as text tracks in the MPD and HLS master playlist (default: true).
+    #[serde(default = "default_dash_text_tracks")]
+    text_tracks: bool,
 }
 
 fn default_dash_audio_codec() -> String { "libopus".to_string() }
 fn default_dash_audio_vbr() -> String { "constrained".to_string() }
 fn default_dash_audio_channels() -> u32 { 2 }
 fn default_dash_segment_duration() -> u32 { 10 }
+fn default_dash_surround_codec() -> String { "libopus".to_string() }
+fn default_dash_surround_bitrate_per_channel() -> u32 { 64 }
+fn default_dash_validate_output() -> bool { true }
+fn default_dash_duration_tolerance() -> f64 { 2.0 }
+fn default_dash_text_tracks() -> bool { true }
+fn default_dash_audio_ladder_min_bitrate() -> u32 { 48 }
 
 fn default_dash_config() -> DashConfig {
     DashConfig {
@@ -435,7 +1029,40 @@ fn default_dash_config() -> DashConfig {
         audio_vbr: default_dash_audio_vbr(),
         audio_channels: default_dash_audio_channels(),
         segment_duration: default_dash_segment_duration(),
+        segment_duration_ms: None,
+        preserve_surround: false,
+        surround_codec: default_dash_surround_codec(),
+        surround_bitrate_per_channel: default_dash_surround_bitrate_per_channel(),
+        force_chapter_keyframes: false,
+        validate_output: default_dash_validate_output(),
+        duration_tolerance: default_dash_duration_tolerance(),
+        audio_ladder: false,
+        audio_ladder_min_bitrate: default_dash_audio_ladder_min_bitrate(),
+        hls_aac: false,
+        preferred_audio_languages: Vec::new(),
+        text_tracks: default_dash_text_tracks(),
+    }
+}
+
+/// Longest accepted DASH segment; the GOP and the forced-keyframe grid follow the segment length.
+const MAX_SEGMENT_SECONDS: f64 = 60.0;
+
+/// DASH segment length in seconds: `segment_duration_ms` when set, otherwise
+/// `segment_duration` as ffmpeg's `-seg_duration` has always read it. Values above
+/// a minute are clamped with a warning.
+fn segment_duration_seconds(dash_config: &DashConfig) -> f64 {
+    let seconds = match dash_config.segment_duration_ms {
+        Some(ms) => ms.max(1) as f64 / 1000.0,
+        None => dash_config.segment_duration.max(1) as f64,
+    };
+    if seconds > MAX_SEGMENT_SECONDS {
+        eprintln!(
+            "Warning: DASH segment duration of {}s is too long (segment_duration is in seconds), using {}s",
+            seconds, MAX_SEGMENT_SECONDS
+        );
+        return MAX_SEGMENT_SECONDS;
     }
+    seconds
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -463,6 +1090,37 @@ struct VideoConfig {
     thumbnail: ThumbnailConfig,
     #[serde(default = "default_preview_sprite_config")]
     preview_sprites: PreviewSpriteConfig,
+    #[serde(default = "default_crop_detect_config")]
+    crop_detect: CropDetectConfig,
+    #[serde(default = "default_hover_preview_config")]
+    hover_preview: HoverPreviewConfig,
+    #[serde(default = "default_auto_chapters_config")]
+    auto_chapters: AutoChaptersConfig,
+    #[serde(default = "default_chunked_encoding_config")]
+    chunked: ChunkedEncodingConfig,
+    /// Decode (and crop/tonemap) once and encode every rung from one ffmpeg process (default: false).
+    #[serde(default)]
+    single_process: bool,
+    /// Keep the uploaded source as `source` in the output directory, so `video_clip` jobs
+    /// can cut from it later (default: false).
+    #[serde(default)]
+    retain_source: bool,
+    /// Also run the audio pipeline on every video for a standalone `audio.{format}` download;
+    /// a `{id}_extract_audio` file next to the upload enables it for one concept (default: false).
+    #[serde(default)]
+    extract_audio: bool,
+    #[serde(default = "default_remux_config")]
+    remux: RemuxConfig,
+    #[serde(default = "default_fast_preview_config")]
+    fast_preview: FastPreviewConfig,
+    #[serde(default = "default_rendition_policy_config")]
+    rendition_policy: RenditionPolicyConfig,
+    #[serde(default = "default_encryption_config")]
+    encryption: EncryptionConfig,
+    #[serde(default = "default_watermark_config")]
+    watermark: WatermarkConfig,
+    #[serde(default = "default_download_config")]
+    download: DownloadConfig,
 }
 
 #[tokio::main]
@@ -482,7 +1140,7 @@ async fn main() {
 
     eprintln!("Config loaded, connecting to ScyllaDB...");
 
-    let db = db::ScyllaDb::connect(&config.scylla_nodes, &config.scylla_keyspace)
+    let db = db::ScyllaDb::connect(&config.scylla_nodes, &config.scylla_keyspace, config.video.fast_preview.enabled, config.video.encryption.enabled)
         .await
         .unwrap_or_else(|e| {
             eprintln!("Failed to connect to ScyllaDB: {}", e);
@@ -817,12 +1475,7 @@ async fn process(db: db::ScyllaDb, config: Config) {
                     concept_id
                 );
                 // Get the concept owner for the by_owner table
-                let owner = db.session.execute_unpaged(&db.get_concept, (&concept_id,))
-                    .await
-                    .ok()
-                    .and_then(|r| r.into_rows_result().ok())
-                    .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
-                    .map(|(_, _, owner, _, _)| owner);
+                let owner = concept_owner(&db, &concept_id).await;
 
                 let _ = db.session.execute_unpaged(&db.mark_concept_processed, (&concept_id,)).await;
                 if let Some(ref owner) = owner {
@@ -833,7 +1486,7 @@ async fn process(db: db::ScyllaDb, config: Config) {
             }
 
             // For special types like vtt_translate, skip file type detection
-            let actual_type = if concept_type == "vtt_translate" {
+            let actual_type = if concept_type == "vtt_translate" || concept_type == "video_clip" {
                 concept_type.clone()
             } else {
                 let detected_type = detect_file_type(&input_file);
@@ -857,7 +1510,7 @@ async fn process(db: db::ScyllaDb, config: Config) {
                     .map_err(|e| format!("picture processing failed: {}", e))
             } else if actual_type == "audio" {
                 println!("processing concept: {} as audio", concept_id);
-                process_audio(concept_id.clone(), &db, &config.audio, &config.whisper, &config.picture, &config.translation, &config.upload_path)
+                process_audio(concept_id.clone(), &db, &config)
                     .await
                     .map_err(|e| format!("audio processing failed: {}", e))
             } else if actual_type == "document_pdf" {
@@ -880,21 +1533,21 @@ async fn process(db: db::ScyllaDb, config: Config) {
                     .map_err(|e| format!("object_3d processing failed: {}", e))
             } else if actual_type == "vtt_translate" {
                 println!("processing concept: {} as vtt_translate", concept_id);
-                process_vtt_translate(concept_id.clone(), &db, &config.translation, &config.upload_path, &config.source_path)
+                process_vtt_translate(concept_id.clone(), &db, &config.translation, &config.video.dash, &config.upload_path, &config.source_path)
                     .await
                     .map_err(|e| format!("vtt_translate processing failed: {}", e))
+            } else if actual_type == "video_clip" {
+                println!("processing concept: {} as video_clip", concept_id);
+                process_video_clip(concept_id.clone(), &db, &config)
+                    .await
+                    .map_err(|e| format!("video_clip processing failed: {}", e))
             } else {
                 eprintln!(
                     "Unknown media type '{}' for concept {}, marking as processed",
                     actual_type, concept_id
                 );
                 // Get the concept owner for the by_owner table
-                let owner = db.session.execute_unpaged(&db.get_concept, (&concept_id,))
-                    .await
-                    .ok()
-                    .and_then(|r| r.into_rows_result().ok())
-                    .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
-                    .map(|(_, _, owner, _, _)| owner);
+                let owner = concept_owner(&db, &concept_id).await;
 
                 let _ = db.session.execute_unpaged(&db.mark_concept_processed, (&concept_id,)).await;
                 if let Some(ref owner) = owner {
@@ -911,10 +1564,89 @@ async fn process(db: db::ScyllaDb, config: Config) {
     }
 }
 
+/// Remove a processed video's input file, or move it into the output directory as `source`
+/// when sources are retained.
+fn finish_video_source(input_file: &str, output_dir: &str, retain: bool) {
+    if !retain {
+        let _ = fs::remove_file(input_file);
+        return;
+    }
+    let retained = format!("{}/source", output_dir);
+    // Uploads and output may sit on different filesystems, where rename fails
+    if fs::rename(input_file, &retained).is_err() {
+        match fs::copy(input_file, &retained) {
+            Ok(_) => {
+                let _ = fs::remove_file(input_file);
+            }
+            Err(e) => eprintln!("Warning: Failed to retain source {}: {}", input_file, e),
+        }
+    }
+}
+
+/// Owner of a concept, for the `_by_owner` tables and per-owner settings.
+async fn concept_owner(db: &db::ScyllaDb, concept_id: &str) -> Option<String> {
+    db.session.execute_unpaged(&db.get_concept, (concept_id,))
+        .await
+        .ok()
+        .and_then(|r| r.into_rows_result().ok())
+        .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
+        .map(|(_, _, owner, _, _)| owner)
+}
+
+/// Per-concept video settings: the content key (stored before anything encrypted is
+/// published) and the video config with the owner's watermark override applied.
+async fn prepare_video_concept(concept_id: &str, db: &db::ScyllaDb, config: &Config) -> Result<(VideoConfig, Option<PackageEncryption>), String> {
+    let encryption = if config.video.encryption.enabled {
+        let enc_config = &config.video.encryption;
+        let key = load_or_create_content_key(enc_config, concept_id)?;
+        let statement = db.insert_content_key.as_ref().ok_or("Content key statement was not prepared")?;
+        db.session.execute_unpaged(statement, (concept_id, key.scheme.as_str(), &key.kid, &key.key, &key.iv))
+            .await
+            .map_err(|e| format!("Failed to store content key: {}", e))?;
+        Some(PackageEncryption {
+            key,
+            license_url: enc_config.license_url.replace("{id}", concept_id),
+            key_url: enc_config.key_url.replace("{id}", concept_id),
+            mp4encrypt_path: enc_config.mp4encrypt_path.clone(),
+        })
+    } else {
+        None
+    };
+
+    let mut video_config = config.video.clone();
+    if !config.video.watermark.owners.is_empty() {
+        let owner = concept_owner(db, concept_id).await;
+        video_config.watermark = config.video.watermark.for_owner(owner.as_deref());
+    }
+    Ok((video_config, encryption))
+}
+
 async fn process_video(concept_id: String, db: &db::ScyllaDb, config: &Config) -> Result<(), String> {
     fs::create_dir_all(format!("{}/{}_processing", config.upload_path, &concept_id))
         .map_err(|e| format!("Failed to create processing directory: {}", e))?;
 
+    let (video_config, encryption) = prepare_video_concept(&concept_id, db, config).await?;
+
+    // Optional first phase: publish one low rung so the video is playable early
+    if config.video.fast_preview.enabled {
+        let input_dir = format!("{}/{}", config.upload_path, concept_id);
+        let output_dir = format!("{}/{}_processing", config.upload_path, concept_id);
+        match transcode_video(&input_dir, &output_dir, &video_config, &config.loudness, EncodePhase::Preview, encryption.as_ref()).await {
+            Ok(()) => {
+                let owner = concept_owner(db, &concept_id).await;
+
+                if let Some(ref statement) = db.mark_concept_playable {
+                    let _ = db.session.execute_unpaged(statement, (&concept_id,)).await;
+                }
+                if let (Some(owner), Some(statement)) = (&owner, &db.mark_concept_playable_by_owner) {
+                    let _ = db.session.execute_unpaged(statement, (owner, &concept_id)).await;
+                }
+                println!("Concept {} is playable, continuing with the full ladder", concept_id);
+            }
+            Err(e) => eprintln!("Warning: Fast preview failed for {}, continuing with the full ladder: {}", concept_id, e),
+        }
+    }
+
     // Extract subtitles, chapters, and transcode video all in parallel
     let input_file = format!("{}/{}", config.upload_path, concept_id);
     let input_dir = format!("{}/{}", config.upload_path, concept_id);
@@ -925,22 +1657,37 @@ async fn process_video(concept_id: String, db: &db::ScyllaDb, config: &Config) -
     let translation_config = config.translation.clone();
     let input_file_chap = input_file.clone();
     let output_dir_chap = output_dir.clone();
+    let auto_chapters_config = config.video.auto_chapters.clone();
     let (_, _, transcode_result) = tokio::join!(
         task::spawn_blocking(move || {
             extract_subtitles_to_vtt(&input_file_sub, &output_dir_sub, &whisper_config, &translation_config);
         }),
         task::spawn_blocking(move || {
-            extract_chapters_to_vtt(&input_file_chap, &output_dir_chap);
+            if !extract_chapters_to_vtt(&input_file_chap, &output_dir_chap) && auto_chapters_config.enabled {
+                generate_auto_chapters(&input_file_chap, &output_dir_chap, &auto_chapters_config);
+            }
         }),
         transcode_video(
             &input_dir,
             &output_dir,
-            &config.video,
+            &video_config,
+            &config.loudness,
+            EncodePhase::Full,
+            encryption.as_ref(),
         )
     );
     let transcode_result: Result<(), String> = transcode_result.map_err(|e| format!("{}", e));
     match transcode_result {
         Ok(()) => {
+            // Captions were extracted alongside the encode; expose them in the final manifests
+            if config.video.dash.text_tracks {
+                let output_dir_tt = output_dir.clone();
+                let dash_config = config.video.dash.clone();
+                let _ = task::spawn_blocking(move || publish_text_tracks(&output_dir_tt, &dash_config)).await;
+            }
+            let output_dir_ds = output_dir.clone();
+            let _ = task::spawn_blocking(move || embed_download_subtitles(&output_dir_ds)).await;
+
             // Check for custom thumbnail and apply it if present
             let custom_thumbnail_path = format!("{}/{}_custom_thumbnail", config.upload_path, concept_id);
             if std::path::Path::new(&custom_thumbnail_path).exists() {
@@ -964,23 +1711,32 @@ async fn process_video(concept_id: String, db: &db::ScyllaDb, config: &Config) -
                 }).await;
             }
 
+            // Standalone audio download, after the poster is final so it becomes the cover
+            let extract_audio_path = format!("{}/{}_extract_audio", config.upload_path, concept_id);
+            if config.video.extract_audio || std::path::Path::new(&extract_audio_path).exists() {
+                extract_video_audio(&format!("{}/{}", config.upload_path, concept_id), &output_dir, config).await;
+                let _ = fs::remove_file(&extract_audio_path);
+            }
+
             // Get the concept owner for the by_owner table
-            let owner = db.session.execute_unpaged(&db.get_concept, (&concept_id,))
-                .await
-                .ok()
-                .and_then(|r| r.into_rows_result().ok())
-                .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
-                .map(|(_, _, owner, _, _)| owner);
+            let owner = concept_owner(db, &concept_id).await;
 
             let _ = db.session.execute_unpaged(&db.mark_concept_processed, (&concept_id,)).await;
             if let Some(ref owner) = owner {
                 let _ = db.session.execute_unpaged(&db.mark_concept_processed_by_owner, (owner, &concept_id)).await;
             }
             let _ = db.session.execute_unpaged(&db.delete_unprocessed_concept, (&concept_id,)).await;
-            let _ = fs::remove_file(format!("{}/{}", config.upload_path, concept_id).as_str());
+            finish_video_source(&format!("{}/{}", config.upload_path, concept_id), &output_dir, config.video.retain_source);
             Ok(())
         }
-        Err(e) => Err(format!("Video transcode failed: {}", e)),
+        Err(e) => {
+            // Include the rendition and validation reports so the job result says what broke
+            let report = ["renditions", "validation"]
+                .iter()
+                .filter_map(|key| read_metadata_entry(&output_dir, key).map(|r| format!(" ({}: {})", key, r)))
+                .collect::<String>();
+            Err(format!("Video transcode failed: {}{}", e, report))
+        }
     }
 }
 
@@ -1369,12 +2125,7 @@ async fn process_object_3d(concept_id: String, db: &db::ScyllaDb, upload_path: &
     }
 
     // Get owner and mark concept as processed
-    let owner = db.session.execute_unpaged(&db.get_concept, (&concept_id,))
-        .await
-        .ok()
-        .and_then(|r| r.into_rows_result().ok())
-        .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
-        .map(|(_, _, owner, _, _)| owner);
+    let owner = concept_owner(db, &concept_id).await;
 
     let _ = db.session.execute_unpaged(&db.mark_concept_processed, (&concept_id,)).await;
     if let Some(ref owner) = owner {
@@ -1547,7 +2298,21 @@ async fn process_object_3d_retexture(medium_id: String, db: &db::ScyllaDb, sourc
     Ok(())
 }
 
-async fn process_vtt_translate(concept_id: String, db: &db::ScyllaDb, translation_config: &TranslationConfig, upload_path: &str, source_path: &str) -> Result<(), String> {
+/// Remove a job concept for good: its job file under `upload_path` and its rows in
+/// `media_concepts`, `media_concepts_by_owner` and `unprocessed_concepts`.
+async fn discard_job_concept(db: &db::ScyllaDb, concept_id: &str, job_path: &str) {
+    let _ = fs::remove_file(job_path);
+    // Get the concept owner for the by_owner table
+    let owner = concept_owner(db, concept_id).await;
+
+    let _ = db.session.execute_unpaged(&db.delete_concept, (concept_id,)).await;
+    if let Some(ref owner) = owner {
+        let _ = db.session.execute_unpaged(&db.delete_concept_by_owner, (owner, concept_id)).await;
+    }
+    let _ = db.session.execute_unpaged(&db.delete_unprocessed_concept, (concept_id,)).await;
+}
+
+async fn process_vtt_translate(concept_id: String, db: &db::ScyllaDb, translation_config: &TranslationConfig, dash_config: &DashConfig, upload_path: &str, source_path: &str) -> Result<(), String> {
     let meta_path = format!("{}/{}", upload_path, concept_id);
     let meta_str = fs::read_to_string(&meta_path)
         .map_err(|e| format!("Failed to read vtt_translate metadata: {}", e))?;
@@ -1566,20 +2331,7 @@ async fn process_vtt_translate(concept_id: String, db: &db::ScyllaDb, translatio
     let source_vtt_path = format!("{}/{}.vtt", captions_dir, meta.source_label);
 
     if !std::path::Path::new(&source_vtt_path).exists() {
-        let _ = fs::remove_file(&meta_path);
-        // Get the concept owner for the by_owner table
-        let owner = db.session.execute_unpaged(&db.get_concept, (&concept_id,))
-            .await
-            .ok()
-            .and_then(|r| r.into_rows_result().ok())
-            .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
-            .map(|(_, _, owner, _, _)| owner);
-
-        let _ = db.session.execute_unpaged(&db.delete_concept, (&concept_id,)).await;
-        if let Some(ref owner) = owner {
-            let _ = db.session.execute_unpaged(&db.delete_concept_by_owner, (owner, &concept_id)).await;
-        }
-        let _ = db.session.execute_unpaged(&db.delete_unprocessed_concept, (&concept_id,)).await;
+        discard_job_concept(db, &concept_id, &meta_path).await;
         return Err(format!("Source subtitle file not found: {}", source_vtt_path));
     }
 
@@ -1625,6 +2377,16 @@ async fn process_vtt_translate(concept_id: String, db: &db::ScyllaDb, translatio
         let list_content = existing.join("\n") + "\n";
         let _ = fs::write(&list_path, list_content);
 
+        // Add the new caption to the published manifests of an already packaged video
+        let medium_dir = format!("{}/{}", source_path, meta.medium_id);
+        if dash_config.text_tracks && std::path::Path::new(&format!("{}/video/video.mpd", medium_dir)).exists() {
+            let dash_config = dash_config.clone();
+            let _ = task::spawn_blocking(move || publish_text_tracks(&medium_dir, &dash_config)).await;
+        }
+        // ...and to the download, if it carries subtitles
+        let medium_dir = format!("{}/{}", source_path, meta.medium_id);
+        let _ = task::spawn_blocking(move || embed_download_subtitles(&medium_dir)).await;
+
         println!(
             "VTT translation complete: {} -> {} for medium {}",
             meta.source_label, output_label, meta.medium_id
@@ -1632,20 +2394,7 @@ async fn process_vtt_translate(concept_id: String, db: &db::ScyllaDb, translatio
     }
 
     // Clean up and mark as processed
-    let _ = fs::remove_file(&meta_path);
-    // Get the concept owner for the by_owner table
-    let owner = db.session.execute_unpaged(&db.get_concept, (&concept_id,))
-        .await
-        .ok()
-        .and_then(|r| r.into_rows_result().ok())
-        .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
-        .map(|(_, _, owner, _, _)| owner);
-
-    let _ = db.session.execute_unpaged(&db.delete_concept, (&concept_id,)).await;
-    if let Some(ref owner) = owner {
-        let _ = db.session.execute_unpaged(&db.delete_concept_by_owner, (owner, &concept_id)).await;
-    }
-    let _ = db.session.execute_unpaged(&db.delete_unprocessed_concept, (&concept_id,)).await;
+    discard_job_concept(db, &concept_id, &meta_path).await;
 
     if success {
         Ok(())
@@ -1654,48 +2403,252 @@ async fn process_vtt_translate(concept_id: String, db: &db::ScyllaDb, translatio
     }
 }
 
-async fn process_picture(concept_id: String, db: &db::ScyllaDb, picture_config: &PictureConfig, upload_path: &str) -> Result<(), String> {
-    fs::create_dir_all(format!("{}/{}_processing", upload_path, &concept_id))
-        .map_err(|e| format!("Failed to create processing directory: {}", e))?;
-    let transcode_result = transcode_picture(
-        format!("{}/{}", upload_path, concept_id).as_str(),
-        format!("{}/{}_processing", upload_path, concept_id).as_str(),
-        picture_config,
-    ).await;
-    match transcode_result {
-        Ok(()) => {
-            // Get the concept owner for the by_owner table
-            let owner = db.session.execute_unpaged(&db.get_concept, (&concept_id,))
-                .await
-                .ok()
-                .and_then(|r| r.into_rows_result().ok())
-                .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
-                .map(|(_, _, owner, _, _)| owner);
+/// Copy the captions and chapters of a processed medium into a clip's output directory,
+/// cut to `start`..`end` and shifted to the clip's timeline. Chapter thumbnails are taken
+/// again from the clip, since a chapter may now start later than its old thumbnail.
+fn slice_captions_and_chapters(medium_dir: &str, output_dir: &str, clip_file: &str, start: f64, end: f64, chapters_config: &AutoChaptersConfig) {
+    let source_captions = format!("{}/captions", medium_dir);
+    let captions_dir = format!("{}/captions", output_dir);
+    let names: Vec<String> = fs::read_to_string(format!("{}/list.txt", source_captions))
+        .unwrap_or_default()
+        .lines()
+        .map(|l| l.trim().trim_end_matches(".vtt").trim_end_matches(".ass").to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let mut saved_files = Vec::new();
+    for name in &names {
+        // ASS tracks are carried over through their WebVTT fallback
+        let Ok(vtt) = fs::read_to_string(format!("{}/{}.vtt", source_captions, name)) else { continue };
+        let sliced = slice_vtt(&vtt, start, end);
+        if !sliced.contains(" --> ") {
+            println!("Caption {} has no cues in the clip range, skipping", name);
+            continue;
+        }
+        if let Err(e) = fs::create_dir_all(&captions_dir).and_then(|_| fs::write(format!("{}/{}.vtt", captions_dir, name), sliced)) {
+            eprintln!("Warning: Failed to write clipped caption {}: {}", name, e);
+            continue;
+        }
+        saved_files.push(format!("{}.vtt", name));
+    }
+    create_list_txt(&captions_dir, &saved_files);
+    if let Some(subtitles) = read_metadata_entry(medium_dir, "subtitles").and_then(|v| v.as_array().cloned()) {
+        let kept: Vec<serde_json::Value> = subtitles
+            .into_iter()
+            .filter(|t| t["name"].as_str().is_some_and(|n| saved_files.contains(&format!("{}.vtt", n))))
+            .collect();
+        write_metadata_entry(output_dir, "subtitles", serde_json::Value::Array(kept));
+    }
 
-            let _ = db.session.execute_unpaged(&db.mark_concept_processed, (&concept_id,)).await;
-            if let Some(ref owner) = owner {
-                let _ = db.session.execute_unpaged(&db.mark_concept_processed_by_owner, (owner, &concept_id)).await;
+    if let Ok(chapters) = fs::read_to_string(format!("{}/chapters.vtt", medium_dir)) {
+        let sliced = slice_vtt(&chapters, start, end);
+        if sliced.contains(" --> ") {
+            if let Err(e) = fs::write(format!("{}/chapters.vtt", output_dir), sliced) {
+                println!("Failed to write chapters.vtt: {}", e);
             }
-            let _ = db.session.execute_unpaged(&db.delete_unprocessed_concept, (&concept_id,)).await;
-            let _ = fs::remove_file(format!("{}/{}", upload_path, concept_id).as_str());
-            Ok(())
         }
-        Err(e) => Err(format!("Picture transcode failed: {}", e)),
+    }
+    if let Some(chapters) = read_metadata_entry(medium_dir, "chapters").and_then(|v| v.as_array().cloned()) {
+        let chapters_dir = format!("{}/chapters", output_dir);
+        let mut entries = Vec::new();
+        for chapter in chapters {
+            let (Some(chapter_start), Some(chapter_end)) = (chapter["start"].as_f64(), chapter["end"].as_f64()) else { continue };
+            if chapter_end <= start || chapter_start >= end {
+                continue;
+            }
+            let new_start = chapter_start.max(start) - start;
+            let new_end = chapter_end.min(end) - start;
+            let mut entry = chapter.clone();
+            entry["start"] = json!((new_start * 1000.0).round() / 1000.0);
+            entry["end"] = json!((new_end * 1000.0).round() / 1000.0);
+            if chapter.get("thumbnail").is_some() {
+                let thumb_file = format!("chapter_{}.jpg", entries.len());
+                let _ = fs::create_dir_all(&chapters_dir);
+                if write_chapter_thumbnail(clip_file, &format!("{}/{}", chapters_dir, thumb_file), new_start, new_end, chapters_config) {
+                    entry["thumbnail"] = json!(format!("chapters/{}", thumb_file));
+                } else if let Some(fields) = entry.as_object_mut() {
+                    fields.remove("thumbnail");
+                }
+            }
+            entries.push(entry);
+        }
+        if !entries.is_empty() {
+            write_metadata_entry(output_dir, "chapters", serde_json::Value::Array(entries));
+        }
     }
 }
 
-async fn process_audio(concept_id: String, db: &db::ScyllaDb, audio_config: &AudioTranscodeConfig, whisper_config: &WhisperConfig, picture_config: &PictureConfig, translation_config: &TranslationConfig, upload_path: &str) -> Result<(), String> {
+/// Derive a new video concept from a time range of an already processed one. The job file
+/// holds `{"medium_id", "start", "end", "crop"}`; times are seconds or VTT-style timestamps
+/// and `crop` is an optional `{"width", "height", "x", "y"}` rectangle in source pixels.
+/// The range is cut from the medium's retained source into a lossless intermediate, which
+/// then goes through the regular video pipeline.
+async fn process_video_clip(concept_id: String, db: &db::ScyllaDb, config: &Config) -> Result<(), String> {
+    let spec_path = format!("{}/{}", config.upload_path, concept_id);
+    let spec_str = fs::read_to_string(&spec_path)
+        .map_err(|e| format!("Failed to read video_clip metadata: {}", e))?;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ClipTime {
+        Seconds(f64),
+        Timestamp(String),
+    }
+
+    #[derive(Deserialize)]
+    struct VideoClipMeta {
+        medium_id: String,
+        start: ClipTime,
+        end: ClipTime,
+        #[serde(default)]
+        crop: Option<CropRect>,
+    }
+
+    let seconds = |t: &ClipTime| match t {
+        ClipTime::Seconds(s) => Some(*s),
+        ClipTime::Timestamp(ts) => parse_vtt_timestamp(ts),
+    };
+    let meta: VideoClipMeta = match serde_json::from_str(&spec_str) {
+        Ok(meta) => meta,
+        Err(e) => {
+            discard_job_concept(db, &concept_id, &spec_path).await;
+            return Err(format!("Failed to parse video_clip metadata: {}", e));
+        }
+    };
+    let medium_dir = format!("{}/{}", config.source_path, meta.medium_id);
+    let source_file = format!("{}/source", medium_dir);
+    let (start, end) = match (seconds(&meta.start), seconds(&meta.end)) {
+        (Some(start), Some(end)) if start >= 0.0 && end > start => (start, end),
+        _ => {
+            discard_job_concept(db, &concept_id, &spec_path).await;
+            return Err("Invalid clip range: start and end must be times with end after start".to_string());
+        }
+    };
+    if !std::path::Path::new(&source_file).exists() {
+        discard_job_concept(db, &concept_id, &spec_path).await;
+        return Err(format!("No retained source at {} (enable video.retain_source)", source_file));
+    }
+
+    let output_dir = format!("{}/{}_processing", config.upload_path, concept_id);
+    fs::create_dir_all(&output_dir)
+        .map_err(|e| format!("Failed to create processing directory: {}", e))?;
+    let (video_config, encryption) = prepare_video_concept(&concept_id, db, config).await?;
+
+    // Frame-accurate cut into FFV1/FLAC, so the ladder is encoded from an exact copy of the range
+    let clip_file = format!("{}/{}_clip.mkv", config.upload_path, concept_id);
+    let mut cut = Command::new("ffmpeg");
+    cut.arg("-nostdin").arg("-y")
+        .arg("-ss").arg(format!("{:.3}", start))
+        .arg("-i").arg(&source_file)
+        .arg("-t").arg(format!("{:.3}", end - start))
+        .arg("-map").arg("0:v:0")
+        .arg("-map").arg("0:a?")
+        .arg("-c:v").arg("ffv1").arg("-level").arg("3").arg("-slices").arg("16")
+        .arg("-c:a").arg("flac");
+    if let Some(crop) = meta.crop {
+        cut.arg("-vf").arg(crop.filter());
+    }
+    cut.arg(&clip_file);
+    println!("Cutting clip {:.3}-{:.3}s of {} for {}", start, end, meta.medium_id, concept_id);
+    let cut_status = task::spawn_blocking(move || cut.status())
+        .await
+        .map_err(|e| format!("Clip cut task panicked: {}", e))?
+        .map_err(|e| format!("Failed to run ffmpeg: {}", e))?;
+    if !cut_status.success() {
+        let _ = fs::remove_file(&clip_file);
+        return Err(format!("Cutting the clip failed with exit code {:?}", cut_status.code()));
+    }
+    write_metadata_entry(&output_dir, "clip", json!({
+        "medium_id": meta.medium_id,
+        "start": start,
+        "end": end,
+        "crop": meta.crop.map(|c| json!({ "width": c.width, "height": c.height, "x": c.x, "y": c.y })),
+    }));
+
+    let medium_dir_s = medium_dir.clone();
+    let output_dir_s = output_dir.clone();
+    let clip_file_s = clip_file.clone();
+    let chapters_config = config.video.auto_chapters.clone();
+    let (_, transcode_result) = tokio::join!(
+        task::spawn_blocking(move || {
+            slice_captions_and_chapters(&medium_dir_s, &output_dir_s, &clip_file_s, start, end, &chapters_config);
+        }),
+        transcode_video(&clip_file, &output_dir, &video_config, &config.loudness, EncodePhase::Full, encryption.as_ref())
+    );
+    if let Err(e) = transcode_result {
+        let _ = fs::remove_file(&clip_file);
+        let report = ["renditions", "validation"]
+            .iter()
+            .filter_map(|key| read_metadata_entry(&output_dir, key).map(|r| format!(" ({}: {})", key, r)))
+            .collect::<String>();
+        return Err(format!("Video transcode failed: {}{}", e, report));
+    }
+
+    if config.video.dash.text_tracks {
+        let output_dir_tt = output_dir.clone();
+        let dash_config = config.video.dash.clone();
+        let _ = task::spawn_blocking(move || publish_text_tracks(&output_dir_tt, &dash_config)).await;
+    }
+    let output_dir_ds = output_dir.clone();
+    let _ = task::spawn_blocking(move || embed_download_subtitles(&output_dir_ds)).await;
+
+    let extract_audio_path = format!("{}/{}_extract_audio", config.upload_path, concept_id);
+    if config.video.extract_audio || std::path::Path::new(&extract_audio_path).exists() {
+        extract_video_audio(&clip_file, &output_dir, config).await;
+        let _ = fs::remove_file(&extract_audio_path);
+    }
+
+    // Get the concept owner for the by_owner table
+    let owner = concept_owner(db, &concept_id).await;
+
+    let _ = db.session.execute_unpaged(&db.mark_concept_processed, (&concept_id,)).await;
+    if let Some(ref owner) = owner {
+        let _ = db.session.execute_unpaged(&db.mark_concept_processed_by_owner, (owner, &concept_id)).await;
+    }
+    let _ = db.session.execute_unpaged(&db.delete_unprocessed_concept, (&concept_id,)).await;
+    let _ = fs::remove_file(&spec_path);
+    // The intermediate is an exact copy of the range, so it serves as the clip's own source
+    finish_video_source(&clip_file, &output_dir, config.video.retain_source);
+    println!("Clip {} of medium {} complete", concept_id, meta.medium_id);
+    Ok(())
+}
+
+async fn process_picture(concept_id: String, db: &db::ScyllaDb, picture_config: &PictureConfig, upload_path: &str) -> Result<(), String> {
     fs::create_dir_all(format!("{}/{}_processing", upload_path, &concept_id))
         .map_err(|e| format!("Failed to create processing directory: {}", e))?;
+    let transcode_result = transcode_picture(
+        format!("{}/{}", upload_path, concept_id).as_str(),
+        format!("{}/{}_processing", upload_path, concept_id).as_str(),
+        picture_config,
+    ).await;
+    match transcode_result {
+        Ok(()) => {
+            // Get the concept owner for the by_owner table
+            let owner = concept_owner(db, &concept_id).await;
+
+            let _ = db.session.execute_unpaged(&db.mark_concept_processed, (&concept_id,)).await;
+            if let Some(ref owner) = owner {
+                let _ = db.session.execute_unpaged(&db.mark_concept_processed_by_owner, (owner, &concept_id)).await;
+            }
+            let _ = db.session.execute_unpaged(&db.delete_unprocessed_concept, (&concept_id,)).await;
+            let _ = fs::remove_file(format!("{}/{}", upload_path, concept_id).as_str());
+            Ok(())
+        }
+        Err(e) => Err(format!("Picture transcode failed: {}", e)),
+    }
+}
+
+async fn process_audio(concept_id: String, db: &db::ScyllaDb, config: &Config) -> Result<(), String> {
+    fs::create_dir_all(format!("{}/{}_processing", config.upload_path, &concept_id))
+        .map_err(|e| format!("Failed to create processing directory: {}", e))?;
 
     // Extract subtitles, chapters, and transcode audio all in parallel
-    let input_file = format!("{}/{}", upload_path, concept_id);
-    let input_dir = format!("{}/{}", upload_path, concept_id);
-    let output_dir = format!("{}/{}_processing", upload_path, concept_id);
+    let input_file = format!("{}/{}", config.upload_path, concept_id);
+    let input_dir = format!("{}/{}", config.upload_path, concept_id);
+    let output_dir = format!("{}/{}_processing", config.upload_path, concept_id);
     let input_file_sub = input_file.clone();
     let output_dir_sub = output_dir.clone();
-    let whisper_config = whisper_config.clone();
-    let translation_config = translation_config.clone();
+    let whisper_config = config.whisper.clone();
+    let translation_config = config.translation.clone();
     let input_file_chap = input_file.clone();
     let output_dir_chap = output_dir.clone();
     let (_, _, transcode_result) = tokio::join!(
@@ -1708,27 +2661,24 @@ async fn process_audio(concept_id: String, db: &db::ScyllaDb, audio_config: &Aud
         transcode_audio(
             &input_dir,
             &output_dir,
-            audio_config,
-            picture_config,
+            &config.audio,
+            &config.picture,
+            &config.loudness,
+            true,
         )
     );
     let transcode_result: Result<(), String> = transcode_result.map_err(|e| format!("{}", e));
     match transcode_result {
         Ok(()) => {
             // Get the concept owner for the by_owner table
-            let owner = db.session.execute_unpaged(&db.get_concept, (&concept_id,))
-                .await
-                .ok()
-                .and_then(|r| r.into_rows_result().ok())
-                .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
-                .map(|(_, _, owner, _, _)| owner);
+            let owner = concept_owner(db, &concept_id).await;
 
             let _ = db.session.execute_unpaged(&db.mark_concept_processed, (&concept_id,)).await;
             if let Some(ref owner) = owner {
                 let _ = db.session.execute_unpaged(&db.mark_concept_processed_by_owner, (owner, &concept_id)).await;
             }
             let _ = db.session.execute_unpaged(&db.delete_unprocessed_concept, (&concept_id,)).await;
-            let _ = fs::remove_file(format!("{}/{}", upload_path, concept_id).as_str());
+            let _ = fs::remove_file(format!("{}/{}", config.upload_path, concept_id).as_str());
             Ok(())
         }
         Err(e) => Err(format!("Audio transcode failed: {}", e)),
@@ -1773,12 +2723,7 @@ async fn process_document_pdf(concept_id: String, db: &db::ScyllaDb, pdf_config:
     thumb_result?;
 
     // Get the concept owner for the by_owner table
-    let owner = db.session.execute_unpaged(&db.get_concept, (&concept_id,))
-        .await
-        .ok()
-        .and_then(|r| r.into_rows_result().ok())
-        .and_then(|rows| rows.maybe_first_row::<(String, String, String, String, bool)>().ok().flatten())
-        .map(|(_, _, owner, _, _)| owner);
+    let owner = concept_owner(&db, &concept_id).await;
 
     let _ = db.session.execute_unpaged(&db.mark_concept_processed, (&concept_id,)).await;
     if let Some(ref owner) = owner {
@@ -1910,18 +2855,18 @@ fn extract_pdf_text(input_file: &str, output_dir: &str) -> Result<(), String> {
     Ok(())
 }
 
-fn probe_subtitle_streams(input_file: &str) -> Vec<(u32, String, String, String)> {
-    // Returns Vec of (stream_index, language, title, codec)
+fn probe_subtitle_streams(input_file: &str) -> Vec<(u32, String, String, String, bool, bool)> {
+    // Returns Vec of (stream_index, language, title, codec, default, forced)
     //
     // Single ffprobe call:
-    // ffprobe -v error -select_streams s -show_entries stream=index,codec_name:stream_tags=language,title -of json <input>
+    // ffprobe -v error -select_streams s -show_entries stream=index,codec_name:stream_tags=language,title:stream_disposition=default,forced -of json <input>
     let mut cmd = Command::new("ffprobe");
     cmd.arg("-v")
         .arg("error")
         .arg("-select_streams")
         .arg("s")
         .arg("-show_entries")
-        .arg("stream=index,codec_name:stream_tags=language,title")
+        .arg("stream=index,codec_name:stream_tags=language,title:stream_disposition=default,forced")
         .arg("-of")
         .arg("json")
         .arg(input_file);
@@ -1960,21 +2905,26 @@ fn probe_subtitle_streams(input_file: &str) -> Vec<(u32, String, String, String)
             None => (String::new(), String::new()),
         };
 
-        result.push((idx, language, title, codec));
+        let (default, forced) = match s.disposition {
+            Some(d) => (d.default != 0, d.forced != 0),
+            None => (false, false),
+        };
+
+        result.push((idx, language, title, codec, default, forced));
     }
 
     result
 }
 
-fn probe_audio_streams(input_file: &str) -> Vec<(u32, String, String, String)> {
-    // Returns Vec of (stream_index, language, title, codec)
+fn probe_audio_streams(input_file: &str) -> Vec<(u32, String, String, String, u32, FfprobeDisposition)> {
+    // Returns Vec of (stream_index, language, title, codec, channels, disposition)
     let mut cmd = Command::new("ffprobe");
     cmd.arg("-v")
         .arg("error")
         .arg("-select_streams")
         .arg("a")
         .arg("-show_entries")
-        .arg("stream=index,codec_name:stream_tags=language,title")
+        .arg("stream=index,codec_name,channels:stream_tags=language,title:stream_disposition=default,comment,visual_impaired,hearing_impaired,dub,original")
         .arg("-of")
         .arg("json")
         .arg(input_file);
@@ -2013,87 +2963,335 @@ fn probe_audio_streams(input_file: &str) -> Vec<(u32, String, String, String)> {
             None => (String::new(), String::new()),
         };
 
-        result.push((idx, language, title, codec));
+        let channels = s.channels.unwrap_or(2);
+        result.push((idx, language, title, codec, channels, s.disposition.unwrap_or_default()));
     }
 
     result
 }
 
+/// First-pass loudnorm measurement, as printed by `loudnorm=print_format=json`.
+#[derive(Deserialize, Debug, Clone)]
+struct LoudnormStats {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+impl LoudnormStats {
+    fn integrated(&self) -> Option<f64> {
+        self.input_i.trim().parse::<f64>().ok().filter(|v| v.is_finite())
+    }
+
+    fn true_peak(&self) -> Option<f64> {
+        self.input_tp.trim().parse::<f64>().ok().filter(|v| v.is_finite())
+    }
+
+    fn range(&self) -> Option<f64> {
+        self.input_lra.trim().parse::<f64>().ok().filter(|v| v.is_finite())
+    }
+}
+
+/// Run the loudnorm measurement pass over one audio stream (0-based audio index).
+/// Returns None for silent streams, where loudness is -inf and normalization makes no sense.
+fn measure_loudness(input_file: &str, audio_idx: usize, loudness_config: &LoudnessConfig) -> Option<LoudnormStats> {
+    let filter = format!(
+        "loudnorm=I={}:TP={}:LRA={}:print_format=json",
+        loudness_config.target_lufs, loudness_config.true_peak, loudness_config.loudness_range
+    );
+    let result = Command::new("ffmpeg")
+        .arg("-nostdin")
+        .arg("-hide_banner")
+        .arg("-analyzeduration").arg("1000M")
+        .arg("-probesize").arg("1000M")
+        .arg("-i").arg(input_file)
+        .arg("-map").arg(format!("0:a:{}", audio_idx))
+        .arg("-af").arg(&filter)
+        .arg("-vn")
+        .arg("-f").arg("null")
+        .arg("-")
+        .output();
+
+    let output = match result {
+        Ok(o) => o,
+        Err(e) => {
+            println!("Failed to run FFmpeg loudnorm measurement for audio stream {}: {}", audio_idx, e);
+            return None;
+        }
+    };
+
+    // The JSON block is the last {...} in stderr
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let start = stderr.rfind('{')?;
+    let end = stderr.rfind('}')?;
+    if end < start {
+        return None;
+    }
+    let stats: LoudnormStats = match serde_json::from_str(&stderr[start..=end]) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Failed to parse loudnorm measurement for audio stream {}: {}", audio_idx, e);
+            return None;
+        }
+    };
+
+    stats.integrated()?;
+    println!(
+        "Measured loudness of audio stream {}: {} LUFS, {} dBTP, LRA {} LU",
+        audio_idx, stats.input_i, stats.input_tp, stats.input_lra
+    );
+    Some(stats)
+}
+
+/// Filter for the second (normalizing) loudnorm pass. loudnorm upsamples to 192 kHz
+/// internally, so callers resample the output back to 48 kHz.
+fn loudnorm_filter(stats: &LoudnormStats, loudness_config: &LoudnessConfig) -> String {
+    format!(
+        "loudnorm=I={}:TP={}:LRA={}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+        loudness_config.target_lufs,
+        loudness_config.true_peak,
+        loudness_config.loudness_range,
+        stats.input_i.trim(),
+        stats.input_tp.trim(),
+        stats.input_lra.trim(),
+        stats.input_thresh.trim(),
+        stats.target_offset.trim()
+    )
+}
+
+/// metadata.json entry for one measured audio stream. `gain_db` is what a player
+/// would apply to reach the target when the file itself was not normalized.
+fn loudness_metadata(audio_idx: usize, stats: &LoudnormStats, loudness_config: &LoudnessConfig) -> serde_json::Value {
+    json!({
+        "stream": audio_idx,
+        "integrated_lufs": stats.integrated(),
+        "true_peak_dbtp": stats.true_peak(),
+        "loudness_range_lu": stats.range(),
+        "target_lufs": loudness_config.target_lufs,
+        "gain_db": stats.integrated().map(|i| loudness_config.target_lufs - i),
+        "normalized": loudness_config.normalize,
+    })
+}
+
+/// ffmpeg channel layout used for a surround rendition with the given channel count.
+/// Opus multichannel (mapping family 1) covers up to 8 channels in Vorbis order.
+fn surround_layout(channels: u32) -> Option<&'static str> {
+    match channels {
+        3 => Some("3.0"),
+        4 => Some("quad"),
+        5 => Some("5.0"),
+        6 => Some("5.1"),
+        7 => Some("6.1"),
+        8 => Some("7.1"),
+        _ => None,
+    }
+}
+
+/// Channel count of the extra surround rendition for a source stream, if one should be produced.
+fn surround_channels(source_channels: u32, dash_config: &DashConfig) -> Option<u32> {
+    if !dash_config.preserve_surround || source_channels <= dash_config.audio_channels {
+        return None;
+    }
+    surround_layout(source_channels).map(|_| source_channels)
+}
+
+/// The (file_path, bitrate_kbps) rungs of one audio track, highest first.
+type AudioRungs = Vec<(String, u32)>;
+
+/// A transcoded audio track: its rungs, language, title, channels and disposition.
+type AudioTrack = (AudioRungs, String, String, u32, FfprobeDisposition);
+
 async fn transcode_audio_streams_for_dash(
     input_file: &str,
     output_dir: &str,
-    audio_bitrate: u32,
-    audio_streams: &[(u32, String, String, String)],
+    audio_bitrates: &[u32],
+    audio_streams: &[(u32, String, String, String, u32, FfprobeDisposition)],
     dash_config: &DashConfig,
-) -> Vec<(String, String, String)> {
-    // Returns Vec of (file_path, language, title) for successfully transcoded audio streams
+    loudness_config: &LoudnessConfig,
+) -> (Vec<AudioTrack>, Vec<AudioTrack>) {
+    // Returns (tracks, hls_aac_tracks): Vecs of (files, language, title, channels, disposition) for successfully
+    // transcoded audio tracks, where files are (file_path, bitrate_kbps) rungs, highest first.
+    // With dash.hls_aac every stream also gets a stereo AAC-LC track at the same rungs, for HLS only.
+    // Every stream is downmixed to dash.audio_channels at each bitrate of audio_bitrates; multichannel
+    // sources additionally get a surround track right after the downmix when dash.preserve_surround is enabled.
     // Transcode all audio streams in parallel
     let mut handles = Vec::new();
 
-    for (audio_idx, (_stream_index, language, title, _codec)) in audio_streams.iter().enumerate() {
-        let output_file = format!("{}/audio_stream_{}.mp4", output_dir, audio_idx);
-
-        let mut cmd = format!(
-            "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M -i '{}' -map 0:a:{} -c:a {} -b:a {}k -vbr {} -ac {} -vn",
-            input_file, audio_idx, dash_config.audio_codec, audio_bitrate, dash_config.audio_vbr, dash_config.audio_channels
-        );
+    for (audio_idx, (_stream_index, language, title, _codec, source_channels, disposition)) in audio_streams.iter().enumerate() {
+        let mut metadata_args = String::new();
 
         // Set language metadata if available
-        if !language.is_empty() {
-            cmd.push_str(&format!(" -metadata:s:a:0 language={}", language));
+        if let Some(code) = container_language_code(language) {
+            metadata_args.push_str(&format!(" -metadata:s:a:0 language={}", code));
         }
         if !title.is_empty() {
-            cmd.push_str(&format!(" -metadata:s:a:0 title='{}'", title.replace('\'', "'\\''")));
+            metadata_args.push_str(&format!(" -metadata:s:a:0 title='{}'", title.replace('\'', "'\\''")));
         }
 
-        cmd.push_str(&format!(" -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'", output_file));
+        // (output_file, codec_args, channels, bitrate_kbps, hls_only)
+        let mut renditions: Vec<(String, String, u32, u32, bool)> = audio_bitrates
+            .iter()
+            .enumerate()
+            .map(|(rung, bitrate)| {
+                let output_file = if rung == 0 {
+                    format!("{}/audio_stream_{}.mp4", output_dir, audio_idx)
+                } else {
+                    format!("{}/audio_stream_{}_{}k.mp4", output_dir, audio_idx, bitrate)
+                };
+                let codec_args = format!(
+                    "-c:a {} -b:a {}k -vbr {} -ac {}",
+                    dash_config.audio_codec, bitrate, dash_config.audio_vbr, dash_config.audio_channels
+                );
+                (output_file, codec_args, dash_config.audio_channels, *bitrate, false)
+            })
+            .collect();
+        if let Some(channels) = surround_channels(*source_channels, dash_config) {
+            let bitrate = dash_config.surround_bitrate_per_channel * channels;
+            let mut codec_args = format!("-c:a {} -b:a {}k -ac {}", dash_config.surround_codec, bitrate, channels);
+            if dash_config.surround_codec == "libopus" {
+                codec_args.push_str(&format!(" -vbr {} -mapping_family 1", dash_config.audio_vbr));
+            }
+            renditions.push((
+                format!("{}/audio_stream_{}_surround.mp4", output_dir, audio_idx),
+                codec_args,
+                channels,
+                bitrate,
+                false,
+            ));
+        }
+        if dash_config.hls_aac {
+            for bitrate in audio_bitrates {
+                renditions.push((
+                    format!("{}/audio_stream_{}_aac_{}k.mp4", output_dir, audio_idx, bitrate),
+                    format!("-c:a aac -profile:a aac_low -b:a {}k -ac {}", bitrate, dash_config.audio_channels),
+                    dash_config.audio_channels,
+                    *bitrate,
+                    true,
+                ));
+            }
+        }
 
+        let input_owned = input_file.to_string();
+        let loudness_owned = loudness_config.clone();
         let language_owned = language.clone();
         let title_owned = title.clone();
-        let output_file_owned = output_file.clone();
+        let disposition = *disposition;
         handles.push(task::spawn_blocking(move || {
-            println!("Executing: {}", cmd);
-            let status = Command::new("sh")
-                .arg("-c")
-                .arg(&cmd)
-                .status();
-            (status, audio_idx, output_file_owned, language_owned, title_owned)
+            // Loudness measurement runs inside the task so all streams are measured in parallel,
+            // and once per stream no matter how many renditions it gets
+            let stats = if loudness_owned.enabled {
+                measure_loudness(&input_owned, audio_idx, &loudness_owned)
+            } else {
+                None
+            };
+            let loudnorm = match stats {
+                Some(ref st) if loudness_owned.normalize => Some(loudnorm_filter(st, &loudness_owned)),
+                _ => None,
+            };
+
+            let mut rendition_results = Vec::new();
+            for (output_file, codec_args, channels, bitrate, hls_only) in renditions {
+                let mut filters: Vec<String> = Vec::new();
+                if let Some(ref f) = loudnorm {
+                    filters.push(f.clone());
+                }
+                if channels > 2 {
+                    // Remap side/back variants (e.g. "5.1(side)") onto the layout the encoder expects
+                    if let Some(layout) = surround_layout(channels) {
+                        filters.push(format!("aformat=channel_layouts={}", layout));
+                    }
+                }
+                let mut filter_args = String::new();
+                if !filters.is_empty() {
+                    filter_args.push_str(&format!(" -af '{}'", filters.join(",")));
+                }
+                if loudnorm.is_some() {
+                    filter_args.push_str(" -ar 48000");
+                }
+
+                let cmd = format!(
+                    "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M -i '{}' -map 0:a:{}{} {} -vn{} -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
+                    input_owned, audio_idx, filter_args, codec_args, metadata_args, output_file
+                );
+                println!("Executing: {}", cmd);
+                let status = Command::new("sh")
+                    .arg("-c")
+                    .arg(&cmd)
+                    .status();
+                rendition_results.push((status, output_file, channels, bitrate, hls_only));
+            }
+            (rendition_results, audio_idx, language_owned, title_owned, disposition, stats)
         }));
     }
 
     // Collect results from all parallel audio transcodes
     let mut result = Vec::new();
+    let mut hls_result = Vec::new();
+    let mut loudness_entries = Vec::new();
     for handle in handles {
         match handle.await {
-            Ok((status, audio_idx, output_file, language, title)) => match status {
-                Ok(s) if s.success() => {
-                    println!(
-                        "Generated audio stream {}: {} (language: {}, title: {})",
-                        audio_idx,
-                        output_file,
-                        if language.is_empty() { "und" } else { &language },
-                        if title.is_empty() { "none" } else { &title }
-                    );
-                    result.push((output_file, language, title));
+            Ok((rendition_results, audio_idx, language, title, disposition, stats)) => {
+                let mut any_success = false;
+                // One track per channel layout (downmix, surround) and codec, each holding its bitrate rungs
+                let mut tracks: Vec<(u32, bool, AudioRungs)> = Vec::new();
+                for (status, output_file, channels, bitrate, hls_only) in rendition_results {
+                    match status {
+                        Ok(s) if s.success() => {
+                            println!(
+                                "Generated audio stream {}: {} (language: {}, title: {}, channels: {}, {}k)",
+                                audio_idx,
+                                output_file,
+                                if language.is_empty() { "und" } else { &language },
+                                if title.is_empty() { "none" } else { &title },
+                                channels,
+                                bitrate
+                            );
+                            match tracks.iter_mut().find(|(c, h, _)| *c == channels && *h == hls_only) {
+                                Some((_, _, files)) => files.push((output_file, bitrate)),
+                                None => tracks.push((channels, hls_only, vec![(output_file, bitrate)])),
+                            }
+                            any_success = true;
+                        }
+                        Ok(s) => {
+                            eprintln!(
+                                "Failed to transcode audio stream {} ({} channels) with exit code: {:?}",
+                                audio_idx,
+                                channels,
+                                s.code()
+                            );
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to execute ffmpeg for audio stream {}: {}", audio_idx, e);
+                        }
+                    }
                 }
-                Ok(s) => {
-                    eprintln!(
-                        "Failed to transcode audio stream {} with exit code: {:?}",
-                        audio_idx,
-                        s.code()
-                    );
+                for (channels, hls_only, files) in tracks {
+                    let track = (files, language.clone(), title.clone(), channels, disposition);
+                    if hls_only {
+                        hls_result.push(track);
+                    } else {
+                        result.push(track);
+                    }
                 }
-                Err(e) => {
-                    eprintln!("Failed to execute ffmpeg for audio stream {}: {}", audio_idx, e);
+                if any_success {
+                    if let Some(ref st) = stats {
+                        loudness_entries.push(loudness_metadata(audio_idx, st, loudness_config));
+                    }
                 }
-            },
+            }
             Err(e) => {
                 eprintln!("Audio transcode task panicked: {}", e);
             }
         }
     }
 
-    result
+    if !loudness_entries.is_empty() {
+        write_metadata_entry(output_dir, "loudness", serde_json::Value::Array(loudness_entries));
+    }
+
+    (result, hls_result)
 }
 
 fn sanitize_filename(name: &str) -> String {
@@ -2102,8 +3300,10 @@ fn sanitize_filename(name: &str) -> String {
         .collect()
 }
 
-/// Normalize a language code to ISO 639-1 (two-letter) format.
-/// Handles ISO 639-3/2T three-letter codes (e.g., "eng", "ces") and full English names.
+/// Normalize a language tag to BCP-47: the primary language as ISO 639-1 where one exists
+/// (ISO 639-3 otherwise), followed by the script ("zh-Hant") and region ("pt-BR") when given.
+/// Accepts ISO 639-1 and 639-2/T or /B codes ("ces", "cze"), full English names ("Czech")
+/// and tags using "_" as separator ("pt_BR"). Returns None for undetermined or unknown tags.
 fn normalize_language_code(code: &str) -> Option<String> {
     use isolang::Language;
 
@@ -2114,21 +3314,106 @@ fn normalize_language_code(code: &str) -> Option<String> {
         return None;
     }
 
-    // Already ISO 639-1 (2-letter) — accept as-is
-    if code_lower.len() == 2 {
-        return Some(code_lower.to_string());
+    let mut subtags = code_lower.split(['-', '_']);
+    let primary = subtags.next()?;
+    let language = match primary.len() {
+        2 => Language::from_639_1(primary),
+        3 => Language::from_639_3(iso639_2b_to_2t(primary)),
+        _ => None,
+    };
+    let Some(language) = language else {
+        // Full English name (already lowercased)
+        return Language::from_name_lowercase(code_lower).map(bcp47_primary_subtag);
+    };
+
+    let mut tag = bcp47_primary_subtag(language);
+    for subtag in subtags {
+        let alphabetic = subtag.chars().all(|c| c.is_ascii_alphabetic());
+        match subtag.len() {
+            // Script, title case: "hant" -> "Hant"
+            4 if alphabetic => {
+                tag.push('-');
+                tag.push_str(&subtag[..1].to_uppercase());
+                tag.push_str(&subtag[1..]);
+            }
+            // Region: "br" -> "BR", or a UN M.49 code such as "419"
+            2 if alphabetic => tag.push_str(&format!("-{}", subtag.to_uppercase())),
+            3 if subtag.chars().all(|c| c.is_ascii_digit()) => tag.push_str(&format!("-{}", subtag)),
+            // Variants and extensions are dropped
+            _ => {}
+        }
     }
+    Some(tag)
+}
+
+fn bcp47_primary_subtag(language: isolang::Language) -> String {
+    language.to_639_1().unwrap_or_else(|| language.to_639_3()).to_string()
+}
 
-    if code_lower.len() == 3 {
-        return Language::from_639_3(code_lower)
-            .and_then(|l| l.to_639_1())
-            .map(str::to_string);
+/// Map ISO 639-2/B (bibliographic) codes, still common in Matroska files, to 639-2/T.
+fn iso639_2b_to_2t(code: &str) -> &str {
+    match code {
+        "alb" => "sqi",
+        "arm" => "hye",
+        "baq" => "eus",
+        "bur" => "mya",
+        "chi" => "zho",
+        "cze" => "ces",
+        "dut" => "nld",
+        "fre" => "fra",
+        "geo" => "kat",
+        "ger" => "deu",
+        "gre" => "ell",
+        "ice" => "isl",
+        "mac" => "mkd",
+        "mao" => "mri",
+        "may" => "msa",
+        "per" => "fas",
+        "rum" => "ron",
+        "slo" => "slk",
+        "tib" => "bod",
+        "wel" => "cym",
+        other => other,
     }
+}
+
+/// Three-letter ISO 639-2/T code for container metadata (MP4 `mdhd` only takes these).
+fn container_language_code(code: &str) -> Option<String> {
+    let tag = normalize_language_code(code)?;
+    let primary = tag.split('-').next()?;
+    let language = if primary.len() == 2 {
+        isolang::Language::from_639_1(primary)
+    } else {
+        isolang::Language::from_639_3(primary)
+    }?;
+    Some(language.to_639_3().to_string())
+}
 
-    // Full English name (already lowercased)
-    Language::from_name_lowercase(code_lower)
-        .and_then(|l| l.to_639_1())
-        .map(str::to_string)
+/// Human-readable English name of a language tag for labels, e.g. "Czech",
+/// "Portuguese (BR)" or "Chinese (Traditional)".
+fn language_display_name(code: &str) -> Option<String> {
+    let tag = normalize_language_code(code)?;
+    let mut subtags = tag.split('-');
+    let primary = subtags.next()?;
+    let language = if primary.len() == 2 {
+        isolang::Language::from_639_1(primary)
+    } else {
+        isolang::Language::from_639_3(primary)
+    }?;
+    let qualifiers: Vec<&str> = subtags
+        .map(|subtag| match subtag {
+            "Hans" => "Simplified",
+            "Hant" => "Traditional",
+            "Latn" => "Latin",
+            "Cyrl" => "Cyrillic",
+            other => other,
+        })
+        .collect();
+    if qualifiers.is_empty() {
+        Some(language.to_name().to_string())
+    } else {
+        Some(format!("{} ({})", language.to_name(), qualifiers.join(", ")))
+    }
 }
 
 /// Detect the language of an audio file using Whisper.cpp verbose_json response.
@@ -2333,7 +3618,7 @@ fn extract_subtitles_to_vtt(input_file: &str, output_dir: &str, whisper_config:
     fs::create_dir_all(&captions_dir).expect("Failed to create captions directory");
 
     // Check if any subtitle stream is ASS/SSA — if so, also extract embedded fonts
-    let has_ass = subtitle_streams.iter().any(|(_, _, _, codec)| is_ass_codec(codec));
+    let has_ass = subtitle_streams.iter().any(|(_, _, _, codec, _, _)| is_ass_codec(codec));
     if has_ass {
         extract_embedded_fonts(input_file, &captions_dir);
     }
@@ -2347,15 +3632,19 @@ fn extract_subtitles_to_vtt(input_file: &str, output_dir: &str, whisper_config:
     let mut ass_outputs: Vec<(u32, String, String, String, String, Option<String>)> = Vec::new();
     let mut vtt_outputs: Vec<(u32, String, String, String, String, Option<String>)> = Vec::new();
 
-    for (stream_idx, language, title, codec) in subtitle_streams {
-        // Try to normalize language tag to ISO 639-1 code
+    // Track descriptions for the manifests, keyed by final name (see publish_text_tracks)
+    let mut track_entries: std::collections::HashMap<String, serde_json::Value> = std::collections::HashMap::new();
+
+    for (stream_idx, language, title, codec, default, forced) in subtitle_streams {
+        // Normalize the language tag to BCP-47
         let iso_code = if !language.is_empty() {
             normalize_language_code(&language)
         } else {
             None
         };
 
-        // When translation is enabled, prefer ISO code as filename
+        // When translation is enabled, prefer the normalized tag as filename; otherwise
+        // files keep their source names and only the published metadata is normalized
         let base_name = if translation_enabled {
             if let Some(ref code) = iso_code {
                 code.clone()
@@ -2399,6 +3688,15 @@ fn extract_subtitles_to_vtt(input_file: &str, output_dir: &str, whisper_config:
             iso_code
         );
 
+        track_entries.insert(final_name.clone(), json!({
+            "name": final_name,
+            "language": iso_code,
+            "language_name": iso_code.as_deref().and_then(language_display_name),
+            "title": title,
+            "default": default,
+            "forced": forced,
+        }));
+
         if is_ass {
             ass_outputs.push((stream_idx, final_name, output_file, language, title, iso_code));
         } else {
@@ -2432,6 +3730,20 @@ fn extract_subtitles_to_vtt(input_file: &str, output_dir: &str, whisper_config:
                     for (_stream_idx, final_name, output_file, _language, _title, iso_code) in ass_outputs {
                         match fs::metadata(&output_file) {
                             Ok(metadata) if metadata.len() > 0 => {
+                                // WebVTT fallback for players and manifests that cannot use ASS
+                                let fallback = format!("{}/{}.vtt", captions_dir, final_name);
+                                let converted = Command::new("ffmpeg")
+                                    .arg("-nostdin")
+                                    .arg("-v").arg("error")
+                                    .arg("-i").arg(&output_file)
+                                    .arg("-c:s").arg("webvtt")
+                                    .arg("-y")
+                                    .arg(&fallback)
+                                    .status();
+                                if !matches!(converted, Ok(status) if status.success()) {
+                                    println!("Failed to convert {} to a WebVTT fallback", output_file);
+                                    let _ = fs::remove_file(&fallback);
+                                }
                                 saved_files.push(format!("{}.ass", final_name));
                                 available_subs.push((final_name, iso_code));
                             }
@@ -2504,6 +3816,12 @@ fn extract_subtitles_to_vtt(input_file: &str, output_dir: &str, whisper_config:
         }
     }
 
+    let tracks: Vec<serde_json::Value> = available_subs
+        .iter()
+        .filter_map(|(name, _)| track_entries.remove(name))
+        .collect();
+    write_metadata_entry(output_dir, "subtitles", serde_json::Value::Array(tracks));
+
     create_list_txt(&captions_dir, &saved_files);
     saved_files
 }
@@ -2760,13 +4078,16 @@ fn compute_split_points(duration: f64, silences: &[SilenceInterval], target_secs
     split_points
 }
 
-/// Parse a VTT timestamp (HH:MM:SS.mmm) into total seconds.
+/// Parse a VTT timestamp (HH:MM:SS.mmm, or MM:SS.mmm as ffmpeg writes below one hour) into total seconds.
 fn parse_vtt_timestamp(ts: &str) -> Option<f64> {
     let parts: Vec<&str> = ts.split(':').collect();
-    if parts.len() != 3 { return None; }
-    let hours: f64 = parts[0].trim().parse().ok()?;
-    let minutes: f64 = parts[1].trim().parse().ok()?;
-    let seconds: f64 = parts[2].trim().parse().ok()?;
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [h, m, s] => (h.trim().parse::<f64>().ok()?, *m, *s),
+        [m, s] => (0.0, *m, *s),
+        _ => return None,
+    };
+    let minutes: f64 = minutes.trim().parse().ok()?;
+    let seconds: f64 = seconds.trim().parse().ok()?;
     Some(hours * 3600.0 + minutes * 60.0 + seconds)
 }
 
@@ -2803,6 +4124,55 @@ fn offset_vtt(vtt: &str, offset_secs: f64) -> String {
     out
 }
 
+/// Cut a VTT string down to the cues overlapping `start`..`end` (seconds), shifted so that
+/// `start` becomes zero. Cues crossing either edge are clamped; cue settings and blocks
+/// without timing (header, STYLE, NOTE) are kept.
+fn slice_vtt(vtt: &str, start: f64, end: f64) -> String {
+    let mut out = String::with_capacity(vtt.len());
+    for block in vtt.replace("\r\n", "\n").split("\n\n") {
+        let block = block.trim_matches('\n');
+        if block.is_empty() {
+            continue;
+        }
+        let lines: Vec<&str> = block.lines().collect();
+        let Some(timing_idx) = lines.iter().position(|l| l.contains(" --> ")) else {
+            out.push_str(block);
+            out.push_str("\n\n");
+            continue;
+        };
+        // Timing line: "00:01:23.456 --> 00:01:27.890 align:start"
+        let Some((cue_start, rest)) = lines[timing_idx].split_once(" --> ") else { continue };
+        let rest = rest.trim();
+        let (cue_end, settings) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let (Some(cue_start), Some(cue_end)) = (parse_vtt_timestamp(cue_start), parse_vtt_timestamp(cue_end)) else {
+            continue;
+        };
+        if cue_end <= start || cue_start >= end {
+            continue;
+        }
+        for line in &lines[..timing_idx] {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "{} --> {}",
+            format_vtt_timestamp(cue_start.max(start) - start),
+            format_vtt_timestamp(cue_end.min(end) - start)
+        ));
+        if !settings.trim().is_empty() {
+            out.push(' ');
+            out.push_str(settings.trim());
+        }
+        out.push('\n');
+        for line in &lines[timing_idx + 1..] {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out
+}
+
 /// Send a single audio file to the Whisper.cpp server and return the VTT text.
 fn whisper_transcribe_file(audio_path: &str, whisper_config: &WhisperConfig, timeout_secs: u64) -> Option<String> {
     let form = match multipart::Form::new()
@@ -3020,6 +4390,40 @@ fn create_list_txt(captions_dir: &str, saved_files: &[String]) {
     }
 }
 
+/// Serializes read-modify-write access to metadata.json across parallel processing stages.
+static METADATA_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Read back one key of `{output_dir}/metadata.json`.
+fn read_metadata_entry(output_dir: &str, key: &str) -> Option<serde_json::Value> {
+    let _guard = METADATA_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let content = fs::read_to_string(format!("{}/metadata.json", output_dir)).ok()?;
+    let mut root: serde_json::Value = serde_json::from_str(&content).ok()?;
+    root.get_mut(key).map(serde_json::Value::take)
+}
+
+/// Set a top-level key in `metadata.json` in the output directory, keeping existing keys.
+/// The web app reads this file for facts discovered during processing (crop, loudness, ...).
+fn write_metadata_entry(output_dir: &str, key: &str, value: serde_json::Value) {
+    let _guard = METADATA_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let metadata_path = format!("{}/metadata.json", output_dir);
+
+    let mut metadata = fs::read_to_string(&metadata_path)
+        .ok()
+        .and_then(|c| serde_json::from_str::<serde_json::Value>(&c).ok())
+        .filter(|v| v.is_object())
+        .unwrap_or_else(|| json!({}));
+    metadata[key] = value;
+
+    match serde_json::to_string_pretty(&metadata) {
+        Ok(content) => {
+            if let Err(e) = fs::write(&metadata_path, content) {
+                eprintln!("Warning: Failed to write metadata.json: {}", e);
+            }
+        }
+        Err(e) => eprintln!("Warning: Failed to serialize metadata.json: {}", e),
+    }
+}
+
 // --- Subtitle translation support ---
 
 /// A single VTT cue with timestamp range and text content.
@@ -3396,7 +4800,9 @@ fn ensure_configured_languages(
     all_names
 }
 
-fn extract_chapters_to_vtt(input_file: &str, output_dir: &str) {
+/// Write `chapters.vtt` from the container's titled chapters.
+/// Returns true if the file was written.
+fn extract_chapters_to_vtt(input_file: &str, output_dir: &str) -> bool {
     // Probe chapters using ffprobe JSON output
     let mut cmd = Command::new("ffprobe");
     cmd.arg("-v")
@@ -3410,22 +4816,22 @@ fn extract_chapters_to_vtt(input_file: &str, output_dir: &str) {
         Ok(o) => o,
         Err(e) => {
             println!("Failed to probe chapters: {}", e);
-            return;
+            return false;
         }
     };
 
     if !output.status.success() {
-        return;
+        return false;
     }
 
     let parsed: FfprobeChaptersOutput = match serde_json::from_slice(&output.stdout) {
         Ok(p) => p,
-        Err(_) => return,
+        Err(_) => return false,
     };
 
     let chapters = match parsed.chapters {
         Some(c) if !c.is_empty() => c,
-        _ => return,
+        _ => return false,
     };
 
     // Build WebVTT content
@@ -3466,32 +4872,252 @@ fn extract_chapters_to_vtt(input_file: &str, output_dir: &str) {
     if vtt_content.len() > "WEBVTT\n\n".len() {
         let output_path = format!("{}/chapters.vtt", output_dir);
         match fs::write(&output_path, &vtt_content) {
-            Ok(_) => println!(
-                "Extracted {} chapters to {}",
-                chapters.len(),
-                output_path
-            ),
+            Ok(_) => {
+                println!(
+                    "Extracted {} chapters to {}",
+                    chapters.len(),
+                    output_path
+                );
+                return true;
+            }
             Err(e) => println!("Failed to write chapters.vtt: {}", e),
         }
     }
+    false
 }
 
-async fn transcode_picture(input_file: &str, output_dir: &str, picture_config: &PictureConfig) -> Result<(), ffmpeg_next::Error> {
-    // Get image dimensions
-    let probe_cmd = format!(
-        "ffprobe -v error -select_streams v:0 -show_entries stream=width,height -of csv=s=x:p=0 '{}'",
-        input_file
-    );
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(&probe_cmd)
+/// Start times of the container's chapters (titled or not), in seconds.
+fn probe_chapter_starts(input_file: &str) -> Vec<f64> {
+    let output = match Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-show_chapters")
+        .arg("-print_format").arg("json")
+        .arg(input_file)
         .output()
-        .map_err(|_| ffmpeg_next::Error::External)?;
-    let dimensions = String::from_utf8_lossy(&output.stdout);
-    let (orig_width, orig_height) = parse_dimensions(&dimensions);
-
-    // Calculate scaled dimensions for HD thumbnail (closest to target while maintaining aspect ratio)
-    let (thumb_width, thumb_height) = calculate_hd_scale(orig_width, orig_height, picture_config.thumbnail_width, picture_config.thumbnail_height);
+    {
+        Ok(o) if o.status.success() => o,
+        _ => return Vec::new(),
+    };
+    let parsed: FfprobeChaptersOutput = match serde_json::from_slice(&output.stdout) {
+        Ok(p) => p,
+        Err(_) => return Vec::new(),
+    };
+    parsed
+        .chapters
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|c| c.start_time.as_deref().and_then(|s| s.parse::<f64>().ok()))
+        .filter(|t| *t > 0.0)
+        .collect()
+}
+
+/// Collect chapter boundary candidates in one pass: scene cuts from `select` +
+/// `metadata=print` on a downscaled video stream, silence gaps from `silencedetect`.
+/// Returns (time, strength) pairs sorted by time; a cut inside a silence gap is the
+/// strongest signal, so the two are merged with a bonus.
+fn detect_chapter_candidates(input_file: &str, chapters_config: &AutoChaptersConfig) -> Vec<(f64, f64)> {
+    let video_filter = format!(
+        "scale=320:-2,select='gt(scene,{})',metadata=mode=print",
+        chapters_config.scene_threshold
+    );
+    let audio_filter = format!(
+        "silencedetect=noise={}dB:d={}",
+        chapters_config.silence_noise_db, chapters_config.silence_min_duration
+    );
+    let output = match Command::new("ffmpeg")
+        .arg("-nostdin")
+        .arg("-v").arg("info")
+        .arg("-i").arg(input_file)
+        .arg("-map").arg("0:v:0")
+        .arg("-map").arg("0:a:0?")
+        .arg("-vf").arg(&video_filter)
+        .arg("-af").arg(&audio_filter)
+        .arg("-f").arg("null")
+        .arg("-")
+        .output()
+    {
+        Ok(o) => o,
+        Err(e) => {
+            println!("Failed to run FFmpeg scene detection: {}", e);
+            return Vec::new();
+        }
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let mut scenes: Vec<(f64, f64)> = Vec::new();
+    let mut silences: Vec<(f64, f64)> = Vec::new();
+    let mut pending_time: Option<f64> = None;
+    let mut silence_start: Option<f64> = None;
+    let value_after = |line: &str, key: &str| -> Option<f64> {
+        let pos = line.find(key)?;
+        line[pos + key.len()..].split_whitespace().next()?.parse::<f64>().ok()
+    };
+    for line in stderr.lines() {
+        if let Some(t) = value_after(line, "pts_time:") {
+            pending_time = Some(t);
+        } else if let Some(score) = value_after(line, "lavfi.scene_score=") {
+            if let Some(t) = pending_time.take() {
+                scenes.push((t, score));
+            }
+        } else if let Some(t) = value_after(line, "silence_start:") {
+            silence_start = Some(t);
+        } else if let Some(t) = value_after(line, "silence_end:") {
+            if let Some(start) = silence_start.take() {
+                silences.push((start.max(0.0), t));
+            }
+        }
+    }
+
+    let mut candidates: Vec<(f64, f64)> = Vec::new();
+    for &(time, score) in &scenes {
+        let in_silence = silences.iter().any(|&(start, end)| time >= start && time <= end);
+        candidates.push((time, if in_silence { score + 1.0 } else { score }));
+    }
+    for &(start, end) in &silences {
+        if !scenes.iter().any(|&(time, _)| time >= start && time <= end) {
+            candidates.push(((start + end) / 2.0, 0.5 + ((end - start) / 10.0).min(0.5)));
+        }
+    }
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+    candidates
+}
+
+/// Pick chapter starts from candidates: within each [min, max] window after the
+/// previous boundary take the strongest candidate (earliest on ties), and force a
+/// split at the maximum length when the window has none.
+fn choose_chapter_boundaries(candidates: &[(f64, f64)], duration: f64, min_len: f64, max_len: f64) -> Vec<f64> {
+    let min_len = min_len.max(1.0);
+    let max_len = max_len.max(min_len * 2.0);
+    let mut starts = vec![0.0];
+    let mut last = 0.0;
+    while duration - last > min_len * 2.0 {
+        let window_end = (last + max_len).min(duration - min_len);
+        let best = candidates
+            .iter()
+            .filter(|(t, _)| *t >= last + min_len && *t <= window_end)
+            .fold(None, |best: Option<(f64, f64)>, &(t, score)| match best {
+                Some((_, best_score)) if best_score >= score => best,
+                _ => Some((t, score)),
+            });
+        let next = match best {
+            Some((t, _)) => t,
+            None if duration - last > max_len => last + max_len,
+            None => break,
+        };
+        starts.push(next);
+        last = next;
+    }
+    starts
+}
+
+/// Write the thumbnail of a chapter spanning `start`..`end`, grabbed slightly after the
+/// cut to avoid transition frames.
+fn write_chapter_thumbnail(input_file: &str, thumb_path: &str, start: f64, end: f64, chapters_config: &AutoChaptersConfig) -> bool {
+    let thumb_time = start + ((end - start) / 2.0).min(1.0);
+    let (w, h) = (chapters_config.thumb_width, chapters_config.thumb_height);
+    let status = Command::new("ffmpeg")
+        .arg("-nostdin").arg("-y")
+        .arg("-ss").arg(format!("{:.3}", thumb_time))
+        .arg("-i").arg(input_file)
+        .arg("-vf").arg(format!(
+            "scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2:black",
+            w, h, w, h
+        ))
+        .arg("-frames:v").arg("1")
+        .arg("-update").arg("1")
+        .arg(thumb_path)
+        .status();
+    matches!(status, Ok(s) if s.success())
+}
+
+/// Fallback chapters for videos without container chapters: boundaries from scene
+/// changes and silence gaps, generic numbered titles, and a thumbnail per chapter start
+/// in `chapters/`. Titles and thumbnails are also listed under `chapters` in metadata.json.
+fn generate_auto_chapters(input_file: &str, output_dir: &str, chapters_config: &AutoChaptersConfig) {
+    let duration = match Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-show_entries").arg("format=duration")
+        .arg("-of").arg("default=noprint_wrappers=1:nokey=1")
+        .arg(input_file)
+        .output()
+    {
+        Ok(o) => String::from_utf8_lossy(&o.stdout).trim().parse::<f64>().unwrap_or(0.0),
+        Err(e) => {
+            println!("Failed to probe duration for auto chapters: {}", e);
+            return;
+        }
+    };
+    if duration < chapters_config.min_chapter_seconds * 2.0 {
+        println!("Video too short for auto chapters ({:.1}s)", duration);
+        return;
+    }
+
+    let candidates = detect_chapter_candidates(input_file, chapters_config);
+    let starts = choose_chapter_boundaries(
+        &candidates,
+        duration,
+        chapters_config.min_chapter_seconds,
+        chapters_config.max_chapter_seconds,
+    );
+    if starts.len() < 2 {
+        println!("Auto chapters: no usable boundaries found");
+        return;
+    }
+    println!("Auto chapters: {} chapters from {} candidates", starts.len(), candidates.len());
+
+    let chapters_dir = format!("{}/chapters", output_dir);
+    if let Err(e) = fs::create_dir_all(&chapters_dir) {
+        println!("Failed to create chapters directory: {}", e);
+    }
+
+    let mut vtt_content = String::from("WEBVTT\n\n");
+    let mut entries = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(duration);
+        let title = format!("{} {}", chapters_config.title_prefix, i + 1);
+        vtt_content.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            format_timestamp_vtt(start), format_timestamp_vtt(end), title
+        ));
+
+        let thumb_file = format!("chapter_{}.jpg", i);
+        if !write_chapter_thumbnail(input_file, &format!("{}/{}", chapters_dir, thumb_file), start, end, chapters_config) {
+            println!("Failed to generate thumbnail for chapter {}", i + 1);
+        }
+
+        entries.push(json!({
+            "start": (start * 1000.0).round() / 1000.0,
+            "end": (end * 1000.0).round() / 1000.0,
+            "title": title,
+            "thumbnail": format!("chapters/{}", thumb_file),
+            "auto": true,
+        }));
+    }
+
+    let output_path = format!("{}/chapters.vtt", output_dir);
+    match fs::write(&output_path, &vtt_content) {
+        Ok(_) => println!("Wrote {} auto chapters to {}", starts.len(), output_path),
+        Err(e) => println!("Failed to write chapters.vtt: {}", e),
+    }
+    write_metadata_entry(output_dir, "chapters", serde_json::Value::Array(entries));
+}
+
+async fn transcode_picture(input_file: &str, output_dir: &str, picture_config: &PictureConfig) -> Result<(), ffmpeg_next::Error> {
+    // Get image dimensions
+    let probe_cmd = format!(
+        "ffprobe -v error -select_streams v:0 -show_entries stream=width,height -of csv=s=x:p=0 '{}'",
+        input_file
+    );
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(&probe_cmd)
+        .output()
+        .map_err(|_| ffmpeg_next::Error::External)?;
+    let dimensions = String::from_utf8_lossy(&output.stdout);
+    let (orig_width, orig_height) = parse_dimensions(&dimensions);
+
+    // Calculate scaled dimensions for HD thumbnail (closest to target while maintaining aspect ratio)
+    let (thumb_width, thumb_height) = calculate_hd_scale(orig_width, orig_height, picture_config.thumbnail_width, picture_config.thumbnail_height);
 
     // Run all three picture transcodes in parallel
     let transcode_cmd = format!(
@@ -3688,7 +5314,9 @@ fn count_video_streams(input_file: &str) -> u32 {
     }
 }
 
-fn extract_additional_audio(input_file: &str, output_dir: &str, audio_config: &AudioTranscodeConfig) -> Result<(), ffmpeg_next::Error> {
+/// Transcode every audio stream after the first to `audio_{n}.{format}`, measured and
+/// normalized like the main stream. Returns the loudness entries of the streams written.
+fn extract_additional_audio(input_file: &str, output_dir: &str, audio_config: &AudioTranscodeConfig, loudness_config: &LoudnessConfig) -> Result<Vec<serde_json::Value>, ffmpeg_next::Error> {
     // Get total number of audio streams
     let count_cmd = format!(
         "ffprobe -v error -select_streams a -show_entries stream=index -of csv=p=0 '{}'",
@@ -3705,6 +5333,7 @@ fn extract_additional_audio(input_file: &str, output_dir: &str, audio_config: &A
     let streams: Vec<&str> = output_str.trim().split('\n').collect();
 
     // Skip the first audio stream (usually the main one) and process additional ones
+    let mut loudness_entries = Vec::new();
     for (idx, _) in streams.iter().enumerate().skip(1) {
         let stream_idx = idx; // 0-based index for ffmpeg (stream 1 is index 0)
         let audio_codec = get_audio_codec_for_stream(input_file, stream_idx as u32);
@@ -3715,10 +5344,20 @@ fn extract_additional_audio(input_file: &str, output_dir: &str, audio_config: &A
             &audio_config.lossy_bitrate
         };
 
+        let loudness_stats = if loudness_config.enabled {
+            measure_loudness(input_file, stream_idx, loudness_config)
+        } else {
+            None
+        };
+        let loudness_args = match loudness_stats {
+            Some(ref st) if loudness_config.normalize => format!(" -af '{}' -ar 48000", loudnorm_filter(st, loudness_config)),
+            _ => String::new(),
+        };
+
         let output_path = format!("{}/audio_{}.{}", output_dir, idx + 1, audio_config.output_format);
         let extract_cmd = format!(
-            "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M -i '{}' -map 0:a:{} -c:a {} -b:a {} -vbr {} -application {} '{}'",
-            input_file, stream_idx, audio_config.codec, bitrate, audio_config.vbr, audio_config.application, output_path
+            "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M -i '{}' -map 0:a:{}{} -c:a {} -b:a {}{} '{}'",
+            input_file, stream_idx, loudness_args, audio_config.codec, bitrate, audio_config.opus_args(), output_path
         );
 
         println!("Executing: {}", extract_cmd);
@@ -3726,12 +5365,18 @@ fn extract_additional_audio(input_file: &str, output_dir: &str, audio_config: &A
             .arg("-c")
             .arg(&extract_cmd)
             .status();
-        if let Err(e) = status {
-            eprintln!("Warning: Failed to extract audio stream {}: {}", idx + 1, e);
+        match status {
+            Ok(s) if s.success() => {
+                if let Some(ref st) = loudness_stats {
+                    loudness_entries.push(loudness_metadata(stream_idx, st, loudness_config));
+                }
+            }
+            Ok(_) => eprintln!("Warning: Failed to extract audio stream {}", idx + 1),
+            Err(e) => eprintln!("Warning: Failed to extract audio stream {}: {}", idx + 1, e),
         }
     }
 
-    Ok(())
+    Ok(loudness_entries)
 }
 
 fn get_audio_codec_for_stream(input_file: &str, stream_idx: u32) -> String {
@@ -3881,7 +5526,10 @@ async fn extract_album_cover(input_file: &str, output_dir: &str, picture_config:
     Ok(())
 }
 
-async fn transcode_audio(input_file: &str, output_dir: &str, audio_config: &AudioTranscodeConfig, picture_config: &PictureConfig) -> Result<(), ffmpeg_next::Error> {
+/// Transcode the audio streams of `input_file` to `audio.{format}` (and `audio_{n}.{format}`).
+/// `extract_cover` takes album art from a video stream; video sources pass false, as their
+/// video is the program rather than a cover.
+async fn transcode_audio(input_file: &str, output_dir: &str, audio_config: &AudioTranscodeConfig, picture_config: &PictureConfig, loudness_config: &LoudnessConfig, extract_cover: bool) -> Result<(), ffmpeg_next::Error> {
     // Detect source codec to determine bitrate
     let source_codec = get_audio_codec(input_file);
     println!("Detected audio codec: {}", source_codec);
@@ -3895,11 +5543,26 @@ async fn transcode_audio(input_file: &str, output_dir: &str, audio_config: &Audi
 
     println!("Using bitrate: {} for {} codec", bitrate, source_codec);
 
+    // Measure loudness of the main audio stream before encoding
+    let loudness_stats = if loudness_config.enabled {
+        let input_owned = input_file.to_string();
+        let loudness_owned = loudness_config.clone();
+        task::spawn_blocking(move || measure_loudness(&input_owned, 0, &loudness_owned))
+            .await
+            .map_err(|_| ffmpeg_next::Error::External)?
+    } else {
+        None
+    };
+    let loudness_args = match loudness_stats {
+        Some(ref st) if loudness_config.normalize => format!(" -af '{}' -ar 48000", loudnorm_filter(st, loudness_config)),
+        _ => String::new(),
+    };
+
     // Transcode to configured format with configured codec
     let output_path = format!("{}/audio.{}", output_dir, audio_config.output_format);
     let transcode_cmd = format!(
-        "ffmpeg -nostdin -y -i '{}' -map 0:a:0 -c:a {} -b:a {} -vbr {} -application {} '{}'",
-        input_file, audio_config.codec, bitrate, audio_config.vbr, audio_config.application, output_path
+        "ffmpeg -nostdin -y -i '{}' -map 0:a:0{} -c:a {} -b:a {}{} '{}'",
+        input_file, loudness_args, audio_config.codec, bitrate, audio_config.opus_args(), output_path
     );
     let transcode_cmd_owned = transcode_cmd.clone();
     let status = task::spawn_blocking(move || {
@@ -3916,19 +5579,22 @@ async fn transcode_audio(input_file: &str, output_dir: &str, audio_config: &Audi
 
     // Extract additional audio streams and album cover in parallel
     let audio_stream_count = count_audio_streams(input_file);
-    let has_video = has_video_stream(input_file);
+    let has_video = extract_cover && has_video_stream(input_file);
 
     let mut handles: Vec<task::JoinHandle<()>> = Vec::new();
 
-    if audio_stream_count > 1 {
+    let additional_handle = if audio_stream_count > 1 {
         println!("Found {} audio streams, extracting additional streams...", audio_stream_count);
         let input_owned = input_file.to_string();
         let output_owned = output_dir.to_string();
         let audio_config_owned = audio_config.clone();
-        handles.push(task::spawn_blocking(move || {
-            let _ = extract_additional_audio(&input_owned, &output_owned, &audio_config_owned);
-        }));
-    }
+        let loudness_owned = loudness_config.clone();
+        Some(task::spawn_blocking(move || {
+            extract_additional_audio(&input_owned, &output_owned, &audio_config_owned, &loudness_owned)
+        }))
+    } else {
+        None
+    };
 
     if has_video {
         println!("Found album cover in audio file, extracting...");
@@ -3944,9 +5610,191 @@ async fn transcode_audio(input_file: &str, output_dir: &str, audio_config: &Audi
         let _ = handle.await;
     }
 
+    // One loudness entry per measured stream, the main one first
+    let mut loudness_entries: Vec<serde_json::Value> = loudness_stats.iter().map(|st| loudness_metadata(0, st, loudness_config)).collect();
+    if let Some(handle) = additional_handle {
+        if let Ok(Ok(entries)) = handle.await {
+            loudness_entries.extend(entries);
+        }
+    }
+    if !loudness_entries.is_empty() {
+        write_metadata_entry(output_dir, "loudness", serde_json::Value::Array(loudness_entries));
+    }
+
     Ok(())
 }
 
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Escape a value for an ffmetadata file.
+fn ffmetadata_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Rewrite `audio_path` with the cues of `chapters_vtt` as chapters and the JPEG `cover` as
+/// front cover art. Ogg gets the cover as a `METADATA_BLOCK_PICTURE` comment, other containers
+/// as an attached picture stream. Returns the number of chapters embedded, or None on failure.
+fn embed_chapters_and_cover(audio_path: &str, chapters_vtt: Option<&str>, cover: Option<(&str, u32, u32)>) -> Option<usize> {
+    let is_ogg = audio_path.ends_with(".ogg") || audio_path.ends_with(".opus") || audio_path.ends_with(".oga");
+    let mut ffmetadata = String::from(";FFMETADATA1\n");
+
+    if let Some((cover_path, width, height)) = cover.filter(|_| is_ogg) {
+        let image = fs::read(cover_path).ok()?;
+        // FLAC picture block: front cover, MIME type, empty description, dimensions, 24-bit color
+        let mut block = Vec::with_capacity(image.len() + 42);
+        block.extend_from_slice(&3u32.to_be_bytes());
+        block.extend_from_slice(&("image/jpeg".len() as u32).to_be_bytes());
+        block.extend_from_slice(b"image/jpeg");
+        block.extend_from_slice(&0u32.to_be_bytes());
+        for value in [width, height, 24, 0, image.len() as u32] {
+            block.extend_from_slice(&value.to_be_bytes());
+        }
+        block.extend_from_slice(&image);
+        ffmetadata.push_str(&format!("METADATA_BLOCK_PICTURE={}\n", ffmetadata_escape(&base64_encode(&block))));
+    }
+
+    let cues = chapters_vtt
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|vtt| parse_vtt_cues(&vtt))
+        .unwrap_or_default();
+    let mut chapters = 0;
+    for cue in &cues {
+        let start = parse_vtt_timestamp(&cue.start);
+        let end = cue.end.split_whitespace().next().and_then(parse_vtt_timestamp);
+        if let (Some(start), Some(end)) = (start, end) {
+            ffmetadata.push_str(&format!(
+                "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+                (start * 1000.0).round() as u64,
+                (end * 1000.0).round() as u64,
+                ffmetadata_escape(cue.text.trim())
+            ));
+            chapters += 1;
+        }
+    }
+
+    let (stem, extension) = audio_path.rsplit_once('.')?;
+    let metadata_path = format!("{}.ffmetadata", stem);
+    let temp_path = format!("{}.tmp.{}", stem, extension);
+    fs::write(&metadata_path, ffmetadata).ok()?;
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args(["-nostdin", "-y", "-i", audio_path, "-i", &metadata_path]);
+    let attach = cover.filter(|_| !is_ogg);
+    if let Some((cover_path, ..)) = attach {
+        cmd.args(["-i", cover_path]);
+    }
+    cmd.args(["-map", "0:a", "-map_metadata", "1", "-map_chapters", "1"]);
+    if is_ogg {
+        // Ogg carries its comments per stream
+        cmd.args(["-map_metadata:s:a", "1:g"]);
+    }
+    if attach.is_some() {
+        cmd.args(["-map", "2:v", "-disposition:v:0", "attached_pic"]);
+    }
+    cmd.args(["-c", "copy", &temp_path]);
+
+    let embedded = matches!(cmd.status(), Ok(status) if status.success()) && fs::rename(&temp_path, audio_path).is_ok();
+    let _ = fs::remove_file(&metadata_path);
+    if !embedded {
+        eprintln!("Warning: Could not embed chapters and cover art in {}", audio_path);
+        let _ = fs::remove_file(&temp_path);
+        return None;
+    }
+    Some(chapters)
+}
+
+/// Run the audio pipeline on a video source for a standalone audio download. The files
+/// (`audio.{format}`, plus `audio_{n}.{format}` for further tracks) land next to `video/`,
+/// with `chapters.vtt` embedded as chapters and the poster frame as cover art. They are
+/// recorded under `audio_extract` in metadata.json, apart from the video's own loudness entry.
+/// Encrypted videos get none, like the download.
+async fn extract_video_audio(input_file: &str, output_dir: &str, config: &Config) {
+    if config.video.encryption.enabled {
+        eprintln!("Warning: Skipping audio extraction, a clear copy would bypass the encryption");
+        return;
+    }
+    // transcode_audio writes its own metadata.json, so it runs in a scratch directory
+    let scratch_dir = format!("{}/audio_extract", output_dir);
+    if let Err(e) = fs::create_dir_all(&scratch_dir) {
+        eprintln!("Warning: Could not create audio extraction directory: {}", e);
+        return;
+    }
+    if let Err(e) = transcode_audio(input_file, &scratch_dir, &config.audio, &config.picture, &config.loudness, false).await {
+        eprintln!("Warning: Audio extraction failed: {}", e);
+        let _ = fs::remove_dir_all(&scratch_dir);
+        return;
+    }
+    let loudness = read_metadata_entry(&scratch_dir, "loudness");
+
+    let output_dir = output_dir.to_string();
+    let suffix = format!(".{}", config.audio.output_format);
+    let (cover_width, cover_height) = (config.video.thumbnail.width, config.video.thumbnail.height);
+    let _ = task::spawn_blocking(move || {
+        let mut names: Vec<String> = fs::read_dir(&scratch_dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .filter(|name| name.starts_with("audio") && name.ends_with(&suffix))
+                    .collect()
+            })
+            .unwrap_or_default();
+        names.sort();
+
+        let chapters_path = format!("{}/chapters.vtt", output_dir);
+        let cover_path = format!("{}/thumbnail.jpg", output_dir);
+        let chapters = std::path::Path::new(&chapters_path).exists().then_some(chapters_path.as_str());
+        let cover = std::path::Path::new(&cover_path)
+            .exists()
+            .then_some((cover_path.as_str(), cover_width, cover_height));
+
+        let mut files = Vec::new();
+        let mut chapter_count = 0;
+        for name in names {
+            let path = format!("{}/{}", output_dir, name);
+            if let Err(e) = fs::rename(format!("{}/{}", scratch_dir, name), &path) {
+                eprintln!("Warning: Could not move extracted audio {}: {}", name, e);
+                continue;
+            }
+            if chapters.is_some() || cover.is_some() {
+                chapter_count = embed_chapters_and_cover(&path, chapters, cover).unwrap_or(0);
+            }
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            files.push(json!({ "file": name, "size": size }));
+        }
+        let _ = fs::remove_dir_all(&scratch_dir);
+
+        println!("Extracted {} audio file(s) from the video", files.len());
+        write_metadata_entry(&output_dir, "audio_extract", json!({
+            "files": files,
+            "chapters": chapter_count,
+            "cover": cover.is_some(),
+            "loudness": loudness,
+        }));
+    }).await;
+}
+
 fn calculate_hd_scale(width: u32, height: u32, target_width: u32, target_height: u32) -> (u32, u32) {
 
     // Calculate aspect ratios
@@ -3978,6 +5826,15 @@ struct ThumbnailCue {
     sprite_idx: u32,
 }
 
+/// Which part of the video pipeline `transcode_video` runs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EncodePhase {
+    /// Lowest rung, manifests and a plain thumbnail only, for an early "playable" state
+    Preview,
+    /// Full ladder, sprites and previews; replaces a published preview atomically
+    Full,
+}
+
 #[derive(Clone, Copy)]
 enum EncoderType {
     Nvenc,
@@ -4047,79 +5904,638 @@ fn detect_hdr(input_file: &str) -> HdrInfo {
     hdr_info
 }
 
-fn build_encoder_params(config: &VideoConfig, _framerate: f32, hdr_info: &HdrInfo) -> (String, String, String, EncoderType) {
-        // Build tonemapping filter if HDR is detected
-        let tonemap_filter = if hdr_info.is_hdr {
-            println!("HDR detected: transfer={:?}, primaries={:?}, space={:?}",
-                hdr_info.color_transfer, hdr_info.color_primaries, hdr_info.color_space);
-            // mobius tonemapping with 10-bit output
-            "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=mobius,zscale=t=bt709:m=bt709:r=tv,format=yuv420p10le".to_string()
-        } else {
-            String::new()
-        };
+/// Visible picture area left after removing black bars, in source pixels.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CropRect {
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+}
 
-        match config.encoder {
-            VideoEncoder::Nvenc => {
-                let settings = config.nvenc.as_ref().expect("NVENC settings required");
+impl CropRect {
+    fn filter(&self) -> String {
+        format!("crop={}:{}:{}:{}", self.width, self.height, self.x, self.y)
+    }
+}
 
-                // If HDR detected, we need to handle tonemapping
-                let hwaccel = if hdr_info.is_hdr {
-                    // For HDR, we process in software then upload to CUDA
-                    "-hwaccel cuda".to_string()
-                } else {
-                    "-hwaccel cuda -hwaccel_output_format cuda".to_string()
-                };
+/// Parse the last `crop=w:h:x:y` suggestion from cropdetect's stderr output.
+/// With `reset=0` the last line covers the whole sample window.
+fn parse_cropdetect_stderr(stderr: &str) -> Option<CropRect> {
+    let last = stderr.lines().rev().find(|line| line.contains("crop="))?;
+    let value = last.split("crop=").nth(1)?.split_whitespace().next()?;
+    let parts: Vec<u32> = value.split(':').filter_map(|p| p.parse().ok()).collect();
+    if parts.len() != 4 || parts[0] == 0 || parts[1] == 0 {
+        return None;
+    }
+    Some(CropRect { width: parts[0], height: parts[1], x: parts[2], y: parts[3] })
+}
 
-                let mut params = format!(
-                    "-c:v {} -preset {} -tier {} -rc {} -cq {} -qmin {} -qmax {}",
-                    settings.codec,
-                    settings.preset,
-                    settings.tier,
-                    settings.rc,
-                    settings.cq,
-                    settings.cq + 10,
-                    settings.cq.saturating_sub(10)
-                );
+/// Detect letterboxing/pillarboxing by running cropdetect on short windows spread
+/// across the video (skipping the first and last 5%, where fades and credits live).
+/// Returns the crop only if enough samples agree on it and it actually removes something.
+fn detect_crop(input_file: &str, duration: f64, width: u32, height: u32, crop_config: &CropDetectConfig) -> Option<CropRect> {
+    if width == 0 || height == 0 {
+        return None;
+    }
 
-                if let Some(la) = settings.lookahead {
-                    params.push_str(&format!(" -lookahead {}", la));
-                }
-                if settings.temporal_aq.unwrap_or(false) {
-                    params.push_str(" -temporal-aq 1");
-                }
-                if let Some(max_kbps) = settings.max_bitrate_kbps {
-                    params.push_str(&format!(" -maxrate {}k -bufsize {}k", max_kbps, max_kbps * 2));
-                }
+    let samples = crop_config.samples.max(1);
+    let span_start = duration * 0.05;
+    let span = duration * 0.9;
+    let filter = format!(
+        "cropdetect=limit={}:round={}:reset=0",
+        crop_config.limit, crop_config.round.max(1)
+    );
 
-                (
-                    hwaccel,
-                    params,
-                    tonemap_filter,
-                    EncoderType::Nvenc,
-                )
+    let mut votes: std::collections::HashMap<CropRect, u32> = std::collections::HashMap::new();
+    for i in 0..samples {
+        let seek = if duration > 0.0 {
+            span_start + span * (i as f64 + 0.5) / samples as f64
+        } else {
+            0.0
+        };
+        let result = Command::new("ffmpeg")
+            .arg("-nostdin")
+            .arg("-v").arg("info")
+            .arg("-ss").arg(format!("{:.3}", seek))
+            .arg("-i").arg(input_file)
+            .arg("-t").arg(format!("{:.3}", crop_config.sample_duration))
+            .arg("-map").arg("0:v:0")
+            .arg("-vf").arg(&filter)
+            .arg("-f").arg("null")
+            .arg("-")
+            .output();
+
+        match result {
+            Ok(output) => {
+                if let Some(rect) = parse_cropdetect_stderr(&String::from_utf8_lossy(&output.stderr)) {
+                    *votes.entry(rect).or_insert(0) += 1;
+                }
             }
-            VideoEncoder::Qsv => {
-                let settings = config.qsv.as_ref().expect("QSV settings required");
+            Err(e) => println!("Failed to run FFmpeg cropdetect at {:.1}s: {}", seek, e),
+        }
+    }
 
-                let hwaccel = if hdr_info.is_hdr {
-                    // For HDR, we need software processing first
-                    String::new()
-                } else {
-                    "-hwaccel qsv -hwaccel_output_format qsv -c:v av1_qsv".to_string()
-                };
+    // Ties go to the largest area, then the smallest offsets, so every phase picks the same crop
+    let (best, count) = votes.into_iter().max_by_key(|(rect, count)| {
+        (*count, rect.width as u64 * rect.height as u64, std::cmp::Reverse((rect.y, rect.x)), rect.width)
+    })?;
+    let agreement = count as f64 / samples as f64;
+    println!("Cropdetect: {} agreed by {}/{} samples", best.filter(), count, samples);
 
-                // Use simpler parameters for Intel Arc compatibility
-                let mut params = format!(
-                    "-c:v {} -preset:v {}",
-                    settings.codec, settings.preset
-                );
+    if agreement < crop_config.min_agreement {
+        println!("Cropdetect result not stable enough ({:.0}% agreement), keeping full frame", agreement * 100.0);
+        return None;
+    }
+    if best.width > width || best.height > height || best.x + best.width > width || best.y + best.height > height {
+        return None;
+    }
+    if best.width == width && best.height == height {
+        return None;
+    }
 
-                if settings.look_ahead_depth > 0 {
-                    params.push_str(&format!(" -extbrc:v 1 -look_ahead_depth:v {}", settings.look_ahead_depth));
-                }
+    Some(best)
+}
 
-                // If a quality value is provided, use la_icq rate control on QSV.
-                if settings.global_quality > 0 {
+/// Where the watermark overlay runs: in software, or with the hardware overlay filter
+/// on frames that stay in GPU memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OverlayBackend {
+    Software,
+    Qsv,
+    Cuda,
+    Vaapi,
+}
+
+/// hwaccel arguments when a logo is overlaid in hardware: `hwupload` needs a named filter
+/// device, shared with the decoder so both overlay inputs live on the same device.
+const QSV_OVERLAY_HWACCEL: &str = "-init_hw_device qsv=qs -filter_hw_device qs -hwaccel qsv -hwaccel_device qs -hwaccel_output_format qsv";
+const CUDA_OVERLAY_HWACCEL: &str = "-init_hw_device cuda=cu -filter_hw_device cu -hwaccel cuda -hwaccel_device cu -hwaccel_output_format cuda";
+
+/// Escape a file path for use as a filter option inside a filtergraph: once for the
+/// option parser (`:`), then once for the graph parser (`,;[]`).
+fn escape_filter_path(path: &str) -> String {
+    let escape = |s: &str, special: &[char]| -> String {
+        s.chars()
+            .flat_map(|c| if c == '\\' || special.contains(&c) { vec!['\\', c] } else { vec![c] })
+            .collect()
+    };
+    escape(&escape(path, &[':']), &[',', ';', '[', ']'])
+}
+
+/// Filtergraph snippet overlaying the watermark logo on a `width` pixels wide video.
+/// It behaves like a single filter, so it can be joined into a comma-separated chain;
+/// `tag` keeps its link labels unique when one graph holds several.
+fn watermark_filter(wm: &WatermarkConfig, width: u32, backend: OverlayBackend, tag: &str) -> String {
+    let logo_width = ((width as f32 * wm.scale.clamp(0.01, 1.0)) as u32 & !1).max(2);
+    let margin = (width as f32 * wm.margin.max(0.0)).round() as u32;
+    let (x, y) = match wm.position {
+        WatermarkPosition::TopLeft => (margin.to_string(), margin.to_string()),
+        WatermarkPosition::TopRight => (format!("W-w-{}", margin), margin.to_string()),
+        WatermarkPosition::BottomLeft => (margin.to_string(), format!("H-h-{}", margin)),
+        WatermarkPosition::BottomRight => (format!("W-w-{}", margin), format!("H-h-{}", margin)),
+        WatermarkPosition::Center => ("(W-w)/2".to_string(), "(H-h)/2".to_string()),
+    };
+    let logo = format!(
+        "movie={},scale={}:-2,format=rgba,colorchannelmixer=aa={:.3}",
+        escape_filter_path(&wm.image), logo_width, wm.opacity.clamp(0.0, 1.0)
+    );
+    // Hardware overlays need the logo uploaded to the filter device
+    let (upload, overlay) = match backend {
+        OverlayBackend::Software => ("", "overlay"),
+        OverlayBackend::Qsv => (",format=bgra,hwupload=extra_hw_frames=16", "overlay_qsv"),
+        OverlayBackend::Cuda => (",format=yuva420p,hwupload", "overlay_cuda"),
+        OverlayBackend::Vaapi => (",hwupload", "overlay_vaapi"),
+    };
+    // The software overlay keeps the main input's bit depth instead of converting to 8-bit yuv420
+    let format = if backend == OverlayBackend::Software { ":format=auto" } else { "" };
+    format!(
+        "null[wmv{tag}];{logo}{upload}[wml{tag}];[wmv{tag}][wml{tag}]{overlay}=x={x}:y={y}{format}",
+        tag = tag, logo = logo, upload = upload, overlay = overlay, x = x, y = y, format = format
+    )
+}
+
+/// A scored poster-frame candidate.
+#[derive(Debug, Clone, Copy)]
+struct PosterCandidate {
+    time: f64,
+    score: f64,
+    brightness: f64,
+    contrast: f64,
+    blur: f64,
+}
+
+/// Read the frame picked by `thumbnail` out of `metadata=print` output:
+/// its pts_time and the signalstats/blurdetect values.
+fn parse_poster_stats(stderr: &str) -> Option<(f64, f64, f64, f64, f64)> {
+    let mut pts_time = None;
+    let mut values: std::collections::HashMap<&str, f64> = std::collections::HashMap::new();
+    for line in stderr.lines() {
+        if let Some(pos) = line.find("pts_time:") {
+            pts_time = line[pos + "pts_time:".len()..].split_whitespace().next().and_then(|v| v.parse::<f64>().ok());
+        }
+        if let Some(pos) = line.find("lavfi.") {
+            if let Some((key, value)) = line[pos..].trim().split_once('=') {
+                if let Ok(v) = value.trim().parse::<f64>() {
+                    values.insert(key, v);
+                }
+            }
+        }
+    }
+    let yavg = *values.get("lavfi.signalstats.YAVG")?;
+    let ymin = *values.get("lavfi.signalstats.YLOW").or(values.get("lavfi.signalstats.YMIN"))?;
+    let ymax = *values.get("lavfi.signalstats.YHIGH").or(values.get("lavfi.signalstats.YMAX"))?;
+    let sat = values.get("lavfi.signalstats.SATAVG").copied().unwrap_or(0.0);
+    let blur = values.get("lavfi.blur").copied().unwrap_or(0.0);
+    Some((pts_time.unwrap_or(0.0), yavg, ymax - ymin, sat, blur))
+}
+
+/// Score a frame from 8-bit luma stats: well exposed, contrasty, colourful and sharp
+/// frames win; near-black and near-white frames (fades, title cards) are pushed to the bottom.
+fn poster_score(brightness: f64, contrast: f64, saturation: f64, blur: f64) -> f64 {
+    let exposure = 1.0 - ((brightness - 128.0).abs() / 128.0).min(1.0);
+    let mut score = 0.4 * (contrast / 219.0).min(1.0)
+        + 0.3 * exposure
+        + 0.1 * (saturation / 64.0).min(1.0)
+        - 0.2 * (blur / 10.0).min(1.0);
+    if !(24.0..=235.0).contains(&brightness) || contrast < 32.0 {
+        score -= 1.0;
+    }
+    score
+}
+
+/// Pick poster frames by running `thumbnail` (most representative frame of a batch)
+/// followed by signalstats and blurdetect on evenly spaced windows, skipping the
+/// configured margin at both ends. Sampling positions are fixed, so the same input
+/// always yields the same ranking. Returns candidates best first.
+fn select_poster_frames(input_file: &str, duration: f64, crop_prefix: &str, thumb_config: &ThumbnailConfig) -> Vec<PosterCandidate> {
+    let samples = thumb_config.samples.max(1);
+    let margin = (thumb_config.edge_skip_percent / 100.0).clamp(0.0, 0.45);
+    let span_start = duration * margin;
+    let span = duration * (1.0 - 2.0 * margin);
+    let filter = format!(
+        "{}thumbnail={},scale=640:-2,format=yuv420p,signalstats,blurdetect,metadata=mode=print",
+        crop_prefix, thumb_config.frames_per_sample.max(2)
+    );
+
+    let mut candidates: Vec<PosterCandidate> = Vec::new();
+    for i in 0..samples {
+        let seek = span_start + span * (i as f64 + 0.5) / samples as f64;
+        let result = Command::new("ffmpeg")
+            .arg("-nostdin")
+            .arg("-v").arg("info")
+            .arg("-ss").arg(format!("{:.3}", seek))
+            .arg("-i").arg(input_file)
+            .arg("-map").arg("0:v:0")
+            .arg("-vf").arg(&filter)
+            .arg("-frames:v").arg("1")
+            .arg("-f").arg("null")
+            .arg("-")
+            .output();
+
+        match result {
+            Ok(output) => {
+                match parse_poster_stats(&String::from_utf8_lossy(&output.stderr)) {
+                    Some((pts_time, brightness, contrast, saturation, blur)) => {
+                        // Timestamps restart at zero after input seeking
+                        let time = (seek + pts_time).min(duration);
+                        candidates.push(PosterCandidate {
+                            time,
+                            score: poster_score(brightness, contrast, saturation, blur),
+                            brightness,
+                            contrast,
+                            blur,
+                        });
+                    }
+                    None => println!("Poster sample at {:.1}s returned no frame stats", seek),
+                }
+            }
+            Err(e) => println!("Failed to run FFmpeg poster scoring at {:.1}s: {}", seek, e),
+        }
+    }
+
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.time.total_cmp(&b.time)));
+    candidates
+}
+
+/// Build the silent looping hover preview: short excerpts spread over the middle 80%
+/// of the video are concatenated into `hover.mp4`, which is then converted to
+/// animated `hover.webp` and `hover.avif`.
+fn generate_hover_preview(input_file: &str, preview_dir: &str, duration: f64, filter_prefix: &str, hover_config: &HoverPreviewConfig) -> Result<(), String> {
+    let segment_duration = hover_config.segment_duration.max(0.1);
+    // Short videos get fewer excerpts; very short ones are used whole
+    let segments = (hover_config.segments.max(1) as f64)
+        .min((duration * 0.8 / segment_duration).floor())
+        .max(1.0) as u32;
+    let (span_start, span) = if duration > segment_duration * 2.0 {
+        (duration * 0.1, duration * 0.8 - segment_duration)
+    } else {
+        (0.0, 0.0)
+    };
+
+    let (w, h) = (hover_config.width & !1, hover_config.height & !1);
+    let mut cmd = Command::new("ffmpeg");
+    cmd.arg("-nostdin").arg("-y");
+    let mut filter_complex = String::new();
+    for i in 0..segments {
+        let seek = if segments > 1 {
+            span_start + span * i as f64 / (segments - 1) as f64
+        } else {
+            span_start + span / 2.0
+        };
+        cmd.arg("-ss").arg(format!("{:.3}", seek))
+            .arg("-t").arg(format!("{:.3}", segment_duration))
+            .arg("-i").arg(input_file);
+        // A watermark in the prefix brings link labels, which must be unique per excerpt
+        let prefix = filter_prefix.replace("[wm", &format!("[wm{}_", i));
+        filter_complex.push_str(&format!(
+            "[{}:v:0]{}fps={},scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2:black,setsar=1,format=yuv420p[v{}];",
+            i, prefix, hover_config.fps.max(1), w, h, w, h, i
+        ));
+    }
+    for i in 0..segments {
+        filter_complex.push_str(&format!("[v{}]", i));
+    }
+    filter_complex.push_str(&format!("concat=n={}:v=1:a=0[out]", segments));
+
+    let mp4_path = format!("{}/hover.mp4", preview_dir);
+    let status = cmd
+        .arg("-filter_complex").arg(&filter_complex)
+        .arg("-map").arg("[out]")
+        .arg("-an")
+        .arg("-c:v").arg("libx264")
+        .arg("-preset").arg("slow")
+        .arg("-crf").arg(hover_config.mp4_crf.to_string())
+        .arg("-pix_fmt").arg("yuv420p")
+        .arg("-movflags").arg("+faststart")
+        .arg(&mp4_path)
+        .status()
+        .map_err(|e| format!("Failed to spawn ffmpeg for hover preview: {}", e))?;
+    if !status.success() {
+        return Err(format!("ffmpeg hover preview failed with exit code: {:?}", status.code()));
+    }
+
+    let webp_status = Command::new("ffmpeg")
+        .arg("-nostdin").arg("-y")
+        .arg("-i").arg(&mp4_path)
+        .arg("-c:v").arg("libwebp")
+        .arg("-loop").arg("0")
+        .arg("-quality").arg(hover_config.webp_quality.min(100).to_string())
+        .arg("-an")
+        .arg(format!("{}/hover.webp", preview_dir))
+        .status();
+    match webp_status {
+        Ok(s) if s.success() => {}
+        Ok(s) => eprintln!("Warning: Animated WebP hover preview failed with exit code: {:?}", s.code()),
+        Err(e) => eprintln!("Warning: Failed to spawn ffmpeg for WebP hover preview: {}", e),
+    }
+
+    let avif_status = Command::new("ffmpeg")
+        .arg("-nostdin").arg("-y")
+        .arg("-i").arg(&mp4_path)
+        .arg("-c:v").arg("libsvtav1")
+        .arg("-crf").arg(hover_config.avif_crf.to_string())
+        .arg("-pix_fmt").arg("yuv420p")
+        .arg("-an")
+        .arg("-f").arg("avif")
+        .arg(format!("{}/hover.avif", preview_dir))
+        .status();
+    match avif_status {
+        Ok(s) if s.success() => {}
+        Ok(s) => eprintln!("Warning: Animated AVIF hover preview failed with exit code: {:?}", s.code()),
+        Err(e) => eprintln!("Warning: Failed to spawn ffmpeg for AVIF hover preview: {}", e),
+    }
+
+    Ok(())
+}
+
+/// Keyframe timestamps of the first video stream, read from packet flags (no decoding).
+fn probe_keyframe_times(input_file: &str) -> Vec<f64> {
+    let output = match Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("packet=pts_time,flags")
+        .arg("-of").arg("csv=p=0")
+        .arg(input_file)
+        .output()
+    {
+        Ok(o) => o,
+        Err(e) => {
+            println!("Failed to probe keyframes: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut times: Vec<f64> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (time, flags) = line.split_once(',')?;
+            if flags.contains('K') { time.trim().parse::<f64>().ok() } else { None }
+        })
+        .collect();
+    times.sort_by(|a, b| a.total_cmp(b));
+    times
+}
+
+/// Split the input into (start, length) chunks of roughly `chunk_duration`, with every
+/// boundary moved to the nearest source keyframe so input seeking is exact. Returns an
+/// empty plan when the input is too short or has too few keyframes to split.
+fn plan_encode_chunks(input_file: &str, duration: f64, chunk_config: &ChunkedEncodingConfig) -> Vec<(f64, f64)> {
+    let chunk_duration = chunk_config.chunk_duration.max(10.0);
+    if duration < chunk_duration * 2.0 {
+        return Vec::new();
+    }
+    let keyframes = probe_keyframe_times(input_file);
+    let mut boundaries = vec![0.0];
+    let mut target = chunk_duration;
+    while target < duration - chunk_duration / 2.0 {
+        let last = *boundaries.last().unwrap_or(&0.0);
+        let nearest = keyframes
+            .iter()
+            .copied()
+            .filter(|t| *t > last + 1.0 && *t < duration - 1.0)
+            .min_by(|a, b| (a - target).abs().total_cmp(&(b - target).abs()));
+        if let Some(t) = nearest {
+            if (t - target).abs() < chunk_duration / 2.0 {
+                boundaries.push(t);
+            }
+        }
+        target += chunk_duration;
+    }
+    if boundaries.len() < 2 {
+        return Vec::new();
+    }
+    boundaries
+        .iter()
+        .enumerate()
+        .map(|(i, start)| (*start, boundaries.get(i + 1).copied().unwrap_or(duration) - start))
+        .collect()
+}
+
+/// Losslessly join encoded chunks with the concat demuxer. Chunk lengths are passed as
+/// `duration` directives so timestamps continue exactly across the joins.
+fn concat_encoded_chunks(parts: &[(String, f64)], list_path: &str, output_file: &str) -> Result<(), String> {
+    let mut list = String::from("ffconcat version 1.0\n");
+    for (part, length) in parts {
+        list.push_str(&format!("file '{}'\nduration {:.6}\n", part.replace('\'', "'\\''"), length));
+    }
+    fs::write(list_path, list).map_err(|e| format!("Failed to write concat list {}: {}", list_path, e))?;
+    let status = Command::new("ffmpeg")
+        .arg("-nostdin").arg("-y")
+        .arg("-f").arg("concat")
+        .arg("-safe").arg("0")
+        .arg("-i").arg(list_path)
+        .arg("-map").arg("0:v")
+        .arg("-c").arg("copy")
+        .arg("-an")
+        .arg("-f").arg("mp4")
+        .arg("-movflags").arg("frag_keyframe+empty_moov+default_base_moof")
+        .arg(output_file)
+        .status()
+        .map_err(|e| format!("Failed to spawn ffmpeg concat: {}", e))?;
+    let _ = fs::remove_file(list_path);
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("ffmpeg concat failed with exit code: {:?}", status.code()))
+    }
+}
+
+/// Codec family the configured encoder produces, e.g. "av1" for `av1_nvenc`.
+fn target_codec_family(config: &VideoConfig) -> Option<String> {
+    let codec = match config.encoder {
+        VideoEncoder::Nvenc => config.nvenc.as_ref().map(|s| s.codec.clone()),
+        VideoEncoder::Qsv => config.qsv.as_ref().map(|s| s.codec.clone()),
+        VideoEncoder::Vaapi => config.vaapi.as_ref().map(|s| s.codec.clone()),
+        VideoEncoder::V4l2m2m => config.v4l2m2m.as_ref().map(|s| s.codec.clone()),
+    }?;
+    codec.split('_').next().map(|family| family.to_string())
+}
+
+/// Bitrate cap of the configured encoder in kbps, which bounds every encoded rung.
+fn encoder_max_bitrate_kbps(config: &VideoConfig) -> Option<u32> {
+    match config.encoder {
+        VideoEncoder::Nvenc => config.nvenc.as_ref().and_then(|s| s.max_bitrate_kbps),
+        VideoEncoder::Qsv => config.qsv.as_ref().and_then(|s| s.max_bitrate_kbps),
+        VideoEncoder::Vaapi => config.vaapi.as_ref().and_then(|s| s.max_bitrate_kbps),
+        VideoEncoder::V4l2m2m => config.v4l2m2m.as_ref().and_then(|s| s.max_bitrate_kbps),
+    }
+}
+
+/// Check whether the source video can be stream-copied as the top rung: same codec
+/// family as the encoder, an allowed profile and pixel format, a bitrate within the top
+/// rung's cap (the encoder's `max_bitrate_kbps`, else `remux.max_bitrate_kbps`),
+/// and keyframes never further apart than one DASH segment. Returns the source keyframe
+/// times, which the encoded rungs then copy so every rung switches at the same frames.
+fn remux_keyframes(input_file: &str, config: &VideoConfig, framerate: f32, source_fps: f32) -> Option<Vec<f64>> {
+    let target_codec = target_codec_family(config)?;
+    let output = Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("v:0")
+        .arg("-show_entries").arg("stream=codec_name,profile,pix_fmt,bit_rate:format=bit_rate")
+        .arg("-of").arg("json")
+        .arg(input_file)
+        .output()
+        .ok()?;
+    let parsed: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    let stream = parsed.get("streams")?.get(0)?;
+    let field = |key: &str| stream.get(key).and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let (codec, profile, pix_fmt) = (field("codec_name"), field("profile"), field("pix_fmt"));
+    // Stream bitrate is often missing in Matroska; the container rate is an upper bound
+    let bit_rate = stream
+        .get("bit_rate")
+        .or_else(|| parsed.get("format").and_then(|f| f.get("bit_rate")))
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(u64::MAX);
+    let kbps = bit_rate / 1000;
+    // An encoded top rung never exceeds the encoder's cap, so neither may the copy
+    let max_kbps = encoder_max_bitrate_kbps(config).unwrap_or(config.remux.max_bitrate_kbps);
+
+    let reason = if codec != target_codec {
+        Some(format!("codec {} is not {}", codec, target_codec))
+    } else if !config.remux.allowed_profiles.iter().any(|p| p.eq_ignore_ascii_case(&profile)) {
+        Some(format!("profile '{}' not allowed", profile))
+    } else if !config.remux.allowed_pix_fmts.contains(&pix_fmt) {
+        Some(format!("pixel format {} not allowed", pix_fmt))
+    } else if kbps > max_kbps as u64 {
+        Some(format!("bitrate {} kbps above {} kbps", kbps, max_kbps))
+    } else if source_fps > framerate {
+        Some(format!("{:.2} fps above fps_cap", source_fps))
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        println!("Top rung will be encoded, source not remuxable: {}", reason);
+        return None;
+    }
+
+    let keyframes = probe_keyframe_times(input_file);
+    let segment_seconds = segment_duration_seconds(&config.dash);
+    let max_gap = keyframes.windows(2).map(|w| w[1] - w[0]).fold(0.0, f64::max);
+    if keyframes.first().is_none_or(|t| *t > 0.5) || max_gap > segment_seconds + 0.001 {
+        println!(
+            "Top rung will be encoded, source keyframe interval {:.2}s exceeds segment duration {:.2}s",
+            max_gap, segment_seconds
+        );
+        return None;
+    }
+    println!("Source {} ({}, {}, {} kbps) matches the top rung, remuxing it", codec, profile, pix_fmt, kbps);
+    Some(keyframes)
+}
+
+/// Keyframe placement shared by every rung: a fixed, closed GOP of one DASH segment,
+/// no scene-cut keyframes, and forced keyframes on the segment grid (or at the given
+/// `keyframe_times`, see `keyframe_schedule`) so all renditions switch at the same frames.
+fn gop_params(encoder: &VideoEncoder, framerate: f32, segment_seconds: f64, keyframe_times: &[f64]) -> String {
+    let gop = ((segment_seconds * framerate as f64).round() as u32).max(1);
+    // The GOP is counted in output frames, so the output rate must really be capped at `framerate`
+    let mut params = format!(" -fpsmax {}", framerate);
+    params.push_str(&match encoder {
+        VideoEncoder::Nvenc => format!(" -g {} -keyint_min {} -forced-idr 1 -no-scenecut 1 -strict_gop 1", gop, gop),
+        VideoEncoder::Qsv => format!(" -g {} -flags +cgop -idr_interval 0 -adaptive_i 0 -adaptive_b 0", gop),
+        VideoEncoder::Vaapi => format!(" -g {} -flags +cgop -idr_interval 0", gop),
+        VideoEncoder::V4l2m2m => format!(" -g {}", gop),
+    });
+
+    if keyframe_times.is_empty() {
+        params.push_str(&format!(" -force_key_frames 'expr:gte(t,n_forced*{})'", segment_seconds));
+    } else {
+        let list = keyframe_times.iter().map(|t| format!("{:.3}", t)).collect::<Vec<_>>().join(",");
+        params.push_str(&format!(" -force_key_frames '{}'", list));
+    }
+    params
+}
+
+/// Segment grid from 0 to `duration` merged with extra keyframe times (e.g. chapter starts).
+fn keyframe_schedule(duration: f64, segment_seconds: f64, extra_keyframes: &[f64]) -> Vec<f64> {
+    let mut times: Vec<f64> = extra_keyframes.iter().copied().filter(|t| *t < duration).collect();
+    let mut t = 0.0;
+    while t < duration {
+        times.push(t);
+        t += segment_seconds;
+    }
+    times.sort_by(|a, b| a.total_cmp(b));
+    times.dedup_by(|a, b| (*a - *b).abs() < 0.001);
+    times
+}
+
+fn build_encoder_params(config: &VideoConfig, framerate: f32, hdr_info: &HdrInfo, keyframe_times: &[f64]) -> (String, String, String, EncoderType) {
+        let gop = gop_params(&config.encoder, framerate, segment_duration_seconds(&config.dash), keyframe_times);
+
+        // Build tonemapping filter if HDR is detected
+        let tonemap_filter = if hdr_info.is_hdr {
+            println!("HDR detected: transfer={:?}, primaries={:?}, space={:?}",
+                hdr_info.color_transfer, hdr_info.color_primaries, hdr_info.color_space);
+            // mobius tonemapping with 10-bit output
+            "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=mobius,zscale=t=bt709:m=bt709:r=tv,format=yuv420p10le".to_string()
+        } else {
+            String::new()
+        };
+
+        match config.encoder {
+            VideoEncoder::Nvenc => {
+                let settings = config.nvenc.as_ref().expect("NVENC settings required");
+
+                // If HDR detected, we need to handle tonemapping
+                let hwaccel = if hdr_info.is_hdr {
+                    // For HDR, we process in software then upload to CUDA
+                    "-hwaccel cuda".to_string()
+                } else {
+                    "-hwaccel cuda -hwaccel_output_format cuda".to_string()
+                };
+
+                let mut params = format!(
+                    "-c:v {} -preset {} -tier {} -rc {} -cq {} -qmin {} -qmax {}",
+                    settings.codec,
+                    settings.preset,
+                    settings.tier,
+                    settings.rc,
+                    settings.cq,
+                    settings.cq + 10,
+                    settings.cq.saturating_sub(10)
+                );
+
+                if let Some(la) = settings.lookahead {
+                    params.push_str(&format!(" -lookahead {}", la));
+                }
+                if settings.temporal_aq.unwrap_or(false) {
+                    params.push_str(" -temporal-aq 1");
+                }
+                if let Some(max_kbps) = settings.max_bitrate_kbps {
+                    params.push_str(&format!(" -maxrate {}k -bufsize {}k", max_kbps, max_kbps * 2));
+                }
+
+                params.push_str(&gop);
+
+                (
+                    hwaccel,
+                    params,
+                    tonemap_filter,
+                    EncoderType::Nvenc,
+                )
+            }
+            VideoEncoder::Qsv => {
+                let settings = config.qsv.as_ref().expect("QSV settings required");
+
+                let hwaccel = if hdr_info.is_hdr {
+                    // For HDR, we need software processing first
+                    String::new()
+                } else {
+                    "-hwaccel qsv -hwaccel_output_format qsv -c:v av1_qsv".to_string()
+                };
+
+                // Use simpler parameters for Intel Arc compatibility
+                let mut params = format!(
+                    "-c:v {} -preset:v {}",
+                    settings.codec, settings.preset
+                );
+
+                if settings.look_ahead_depth > 0 {
+                    params.push_str(&format!(" -extbrc:v 1 -look_ahead_depth:v {}", settings.look_ahead_depth));
+                }
+
+                // If a quality value is provided, use la_icq rate control on QSV.
+                if settings.global_quality > 0 {
                     // global_quality is the QSV quality knob used by la_icq/ICQ-style modes
                     params.push_str(&format!(" -global_quality:v {}", settings.global_quality));
                 }
@@ -4127,6 +6543,8 @@ fn build_encoder_params(config: &VideoConfig, _framerate: f32, hdr_info: &HdrInf
                     params.push_str(&format!(" -maxrate {}k -bufsize {}k", max_kbps, max_kbps * 2));
                 }
 
+                params.push_str(&gop);
+
                 (
                     hwaccel,
                     params,
@@ -4149,7 +6567,7 @@ fn build_encoder_params(config: &VideoConfig, _framerate: f32, hdr_info: &HdrInf
                 // (CQP) with -maxrate (VBR) produces conflicting mode signals that VAAPI
                 // drivers silently ignore, making the cap ineffective.  In VBR mode we
                 // drop -qp and drive the encoder with a bitrate ceiling instead.
-                let params = if let Some(max_kbps) = settings.max_bitrate_kbps {
+                let mut params = if let Some(max_kbps) = settings.max_bitrate_kbps {
                     format!(
                         "-c:v {} -b:v {}k -maxrate {}k -bufsize {}k -compression_level 7",
                         settings.codec, max_kbps, max_kbps, max_kbps * 2
@@ -4161,6 +6579,8 @@ fn build_encoder_params(config: &VideoConfig, _framerate: f32, hdr_info: &HdrInf
                     )
                 };
 
+                params.push_str(&gop);
+
                 (
                     hwaccel,
                     params,
@@ -4184,6 +6604,8 @@ fn build_encoder_params(config: &VideoConfig, _framerate: f32, hdr_info: &HdrInf
                     params.push_str(&format!(" -maxrate {}k -bufsize {}k", max_kbps, max_kbps * 2));
                 }
 
+                params.push_str(&gop);
+
                 (
                     hwaccel,
                     params,
@@ -4204,16 +6626,21 @@ fn format_timestamp_vtt(seconds: f64) -> String {
     format!("{:02}:{:02}:{:02}.{:03}", hours, minutes, secs, millis)
 }
 
-fn compute_audio_labels(audio_info: &[(String, String, String)]) -> Vec<String> {
+fn compute_audio_labels(audio_info: &[AudioTrack]) -> Vec<String> {
     let mut raw_labels: Vec<String> = Vec::with_capacity(audio_info.len());
-    for (_, language, title) in audio_info {
+    for (_, language, title, channels, _) in audio_info {
         let label = if !title.is_empty() {
             title.clone()
         } else if !language.is_empty() {
-            language.clone()
+            language_display_name(language).unwrap_or_else(|| language.clone())
         } else {
             "Track".to_string()
         };
+        // Surround renditions sit next to their stereo downmix; name the layout to tell them apart
+        let label = match surround_layout(*channels) {
+            Some(layout) => format!("{} ({})", label, layout),
+            None => label,
+        };
         raw_labels.push(label);
     }
 
@@ -4240,17 +6667,98 @@ fn compute_audio_labels(audio_info: &[(String, String, String)]) -> Vec<String>
     labels
 }
 
+/// Pick the audio rendition players should start with: the first track in the most
+/// preferred language (`dash.preferred_audio_languages`), then the source's default
+/// track, then its original-language track, then the first one. Commentary and
+/// accessibility tracks are only picked when there is nothing else.
+fn choose_default_audio(audio_info: &[AudioTrack], preferred_languages: &[String]) -> usize {
+    let regular: Vec<usize> = (0..audio_info.len())
+        .filter(|&i| {
+            let d = &audio_info[i].4;
+            d.comment == 0 && d.visual_impaired == 0 && d.hearing_impaired == 0
+        })
+        .collect();
+    let candidates = if regular.is_empty() { (0..audio_info.len()).collect() } else { regular };
+    let source_default = |indices: &[usize]| indices.iter().copied().find(|&i| audio_info[i].4.default != 0);
+
+    for preferred in preferred_languages {
+        let Some(wanted) = normalize_language_code(preferred) else { continue };
+        // Exact tag first ("pt-BR"), then the same primary language ("pt")
+        let primary = |tag: &str| tag.split('-').next().unwrap_or("").to_string();
+        let tags: Vec<(usize, String)> = candidates
+            .iter()
+            .filter_map(|&i| normalize_language_code(&audio_info[i].1).map(|tag| (i, tag)))
+            .collect();
+        let mut matching: Vec<usize> = tags.iter().filter(|(_, tag)| *tag == wanted).map(|(i, _)| *i).collect();
+        if matching.is_empty() {
+            matching = tags.iter().filter(|(_, tag)| primary(tag) == primary(&wanted)).map(|(i, _)| *i).collect();
+        }
+        if let Some(i) = source_default(&matching).or(matching.first().copied()) {
+            return i;
+        }
+    }
+
+    source_default(&candidates)
+        .or_else(|| candidates.iter().copied().find(|&i| audio_info[i].4.original != 0))
+        .or(candidates.first().copied())
+        .unwrap_or(0)
+}
+
+/// Roles of an audio rendition derived from the source stream's disposition:
+/// (DASH roles, DASH Accessibility descriptors, HLS CHARACTERISTICS).
+fn audio_track_roles(
+    disposition: &FfprobeDisposition,
+    is_default: bool,
+    track_count: usize,
+) -> (Vec<&'static str>, Vec<(&'static str, &'static str)>, Vec<&'static str>) {
+    const AUDIO_PURPOSE: &str = "urn:tva:metadata:cs:AudioPurposeCS:2007";
+    let mut roles = Vec::new();
+    let mut accessibility = Vec::new();
+    let mut characteristics = Vec::new();
+
+    let special = disposition.comment != 0 || disposition.dub != 0
+        || disposition.visual_impaired != 0 || disposition.hearing_impaired != 0;
+    if is_default {
+        roles.push("main");
+    } else if track_count > 1 && !special {
+        roles.push("alternate");
+    }
+    if disposition.comment != 0 {
+        roles.push("commentary");
+    }
+    if disposition.dub != 0 {
+        roles.push("dub");
+    }
+    if disposition.visual_impaired != 0 {
+        // Audio description for the visually impaired
+        roles.push("description");
+        accessibility.push((AUDIO_PURPOSE, "1"));
+        characteristics.push("public.accessibility.describes-video");
+    }
+    if disposition.hearing_impaired != 0 {
+        // Clean audio / dialogue enhancement for the hard of hearing
+        roles.push("enhanced-audio-intelligibility");
+        accessibility.push((AUDIO_PURPOSE, "2"));
+        characteristics.push("public.accessibility.enhances-speech-intelligibility");
+    }
+    (roles, accessibility, characteristics)
+}
+
 fn post_process_hls_manifest(
     m3u8_path: &str,
-    audio_info: &[(String, String, String)],
+    audio_info: &[AudioTrack],
+    aac_info: &[AudioTrack],
+    default_audio: usize,
+    audio_ladder: &[u32],
+    variant_audio_bitrates: &[u32],
 ) {
     // Replace generic NAME="audio_X" with actual language/title labels in the HLS master playlist.
-    if audio_info.is_empty() {
+    if audio_info.is_empty() && aac_info.is_empty() {
         return;
     }
 
-    let content = match fs::read_to_string(m3u8_path) {
-        Ok(c) => c,
+    let mut playlist = match manifest::Playlist::load(m3u8_path) {
+        Ok(p) => p,
         Err(e) => {
             eprintln!("Warning: Could not read HLS master playlist for post-processing: {}", e);
             return;
@@ -4258,125 +6766,1004 @@ fn post_process_hls_manifest(
     };
 
     let labels = compute_audio_labels(audio_info);
-
-    let mut result = String::with_capacity(content.len());
+    let aac_labels = compute_audio_labels(aac_info);
+    // AAC tracks mirror the source streams, so default to the same stream as the Opus tracks
+    let default_aac = aac_info
+        .iter()
+        .position(|(_, language, title, ..)| {
+            audio_info.get(default_audio).is_some_and(|(_, l, t, ..)| l == language && t == title)
+        })
+        .unwrap_or_else(|| choose_default_audio(aac_info, &[]));
+
+    // With an audio ladder every rung gets its own rendition group and each video variant
+    // points at the group of its rung. Surround tracks have one rung and join the top group.
+    // AAC renditions get groups of their own, paired with a copy of every variant.
+    let regroup = audio_ladder.len() > 1 || !aac_info.is_empty();
+    let group_of = |prefix: &str, bitrate: u32| -> String {
+        let rung = audio_ladder.iter().position(|b| *b == bitrate).unwrap_or(0);
+        format!("{}_{}k", prefix, audio_ladder[rung])
+    };
+    // ffmpeg writes one EXT-X-MEDIA per audio stream, in output order: every rung of every
+    // Opus track, then every rung of every AAC track
+    let renditions: Vec<(bool, usize, u32)> = audio_info
+        .iter()
+        .enumerate()
+        .flat_map(|(track, (files, ..))| files.iter().map(move |(_, bitrate)| (false, track, *bitrate)))
+        .chain(
+            aac_info
+                .iter()
+                .enumerate()
+                .flat_map(|(track, (files, ..))| files.iter().map(move |(_, bitrate)| (true, track, *bitrate))),
+        )
+        .collect();
     let mut audio_idx = 0;
 
-    for line in content.lines() {
-        if line.contains("#EXT-X-MEDIA:") && line.contains("TYPE=AUDIO") && audio_idx < labels.len() {
-            // Replace NAME="audio_X" with NAME="<label>"
-            let mut new_line = String::new();
-            let name_prefix = "NAME=\"";
-            if let Some(name_start) = line.find(name_prefix) {
-                let after_name = name_start + name_prefix.len();
-                if let Some(name_end) = line[after_name..].find('"') {
-                    new_line.push_str(&line[..after_name]);
-                    new_line.push_str(&labels[audio_idx]);
-                    new_line.push_str(&line[after_name + name_end..]);
-                    result.push_str(&new_line);
-                } else {
-                    result.push_str(line);
+    for media in playlist.tags_mut("EXT-X-MEDIA") {
+        if media.attr("TYPE") != Some("AUDIO") || audio_idx >= renditions.len() {
+            continue;
+        }
+        let (is_aac, track, bitrate) = renditions[audio_idx];
+        let (info, track_labels, default_track) = if is_aac {
+            (aac_info, &aac_labels, default_aac)
+        } else {
+            (audio_info, &labels, default_audio)
+        };
+        let (_, language, _, channels, disposition) = &info[track];
+        let is_default = track == default_track;
+        media.set_quoted("NAME", &track_labels[track]);
+        if regroup {
+            media.set_quoted("GROUP-ID", &group_of(if is_aac { "aac" } else { "audio" }, bitrate));
+        }
+        if let Some(lang) = normalize_language_code(language) {
+            media.set_quoted("LANGUAGE", &lang);
+        }
+        // Commentary should never be picked just because its language matches
+        media.set_plain("DEFAULT", if is_default { "YES" } else { "NO" });
+        media.set_plain("AUTOSELECT", if is_default || disposition.comment == 0 { "YES" } else { "NO" });
+        // Advertise the channel count so players can pick stereo vs surround
+        if media.attr("CHANNELS").is_none() {
+            media.set_quoted("CHANNELS", &channels.to_string());
+        }
+        let (_, _, characteristics) = audio_track_roles(disposition, is_default, info.len());
+        if characteristics.is_empty() {
+            media.remove_attr("CHARACTERISTICS");
+        } else {
+            media.set_quoted("CHARACTERISTICS", &characteristics.join(","));
+        }
+        audio_idx += 1;
+    }
+
+    if regroup {
+        // ffmpeg adds the largest audio bitrate to every variant; account for the largest
+        // rendition of the paired group instead, which is the surround track in the top group
+        let max_audio_bps = renditions.iter().map(|(_, _, bitrate)| *bitrate).max().unwrap_or(0) as u64 * 1000;
+        let pair = |variant: &mut manifest::HlsLine, is_aac: bool, rung_bitrate: u32| {
+            let prefix = if is_aac { "aac" } else { "audio" };
+            let group = group_of(prefix, rung_bitrate);
+            let group_bps = renditions
+                .iter()
+                .filter(|(aac, _, bitrate)| *aac == is_aac && group_of(prefix, *bitrate) == group)
+                .map(|(_, _, bitrate)| *bitrate)
+                .max()
+                .unwrap_or(rung_bitrate) as u64
+                * 1000;
+            variant.set_quoted("AUDIO", &group);
+            for key in ["BANDWIDTH", "AVERAGE-BANDWIDTH"] {
+                if let Some(bandwidth) = variant.attr(key).and_then(|v| v.parse::<u64>().ok()) {
+                    let adjusted = bandwidth.saturating_sub(max_audio_bps) + group_bps;
+                    variant.set_plain(key, &adjusted.to_string());
                 }
+            }
+        };
+
+        let mut lines = Vec::with_capacity(playlist.lines.len() * 2);
+        let mut variants = variant_audio_bitrates.iter();
+        let mut pending_aac: Option<manifest::HlsLine> = None;
+        for mut line in std::mem::take(&mut playlist.lines) {
+            if line.is_tag("EXT-X-STREAM-INF") {
+                let rung_bitrate = variants.next().copied().unwrap_or(audio_ladder[0]);
+                if !aac_info.is_empty() {
+                    let mut aac_variant = line.clone();
+                    pair(&mut aac_variant, true, rung_bitrate);
+                    // ffmpeg lists the video codec first, followed by the audio codec
+                    if let Some(video_codec) = line.attr("CODECS").and_then(|c| c.split(',').next()).map(str::to_string) {
+                        aac_variant.set_quoted("CODECS", &format!("{},mp4a.40.2", video_codec));
+                    }
+                    pending_aac = Some(aac_variant);
+                }
+                pair(&mut line, false, rung_bitrate);
+                lines.push(line);
+            } else if let (manifest::HlsLine::Uri(uri), Some(aac_variant)) = (&line, pending_aac.take()) {
+                // The AAC variant plays the same video playlist with the AAC rendition group
+                let uri = uri.clone();
+                lines.push(line);
+                lines.push(aac_variant);
+                lines.push(manifest::HlsLine::Uri(uri));
             } else {
-                result.push_str(line);
+                lines.push(line);
             }
-            audio_idx += 1;
-        } else {
-            result.push_str(line);
         }
-        result.push('\n');
+        playlist.lines = lines;
     }
 
-    if let Err(e) = fs::write(m3u8_path, result) {
+    if let Err(e) = playlist.save(m3u8_path) {
         eprintln!("Warning: Could not write post-processed HLS master playlist: {}", e);
     } else {
-        println!("Post-processed HLS master playlist with {} audio label(s): {:?}", audio_idx, labels);
+        println!("Post-processed HLS master playlist with {} audio rendition(s): {:?}", audio_idx, labels);
     }
 }
 
 fn post_process_dash_manifest(
     mpd_path: &str,
-    audio_info: &[(String, String, String)], // Vec of (file_path, language, title)
+    audio_info: &[AudioTrack], // Vec of (files, language, title, channels, disposition)
+    default_audio: usize,
+    hls_only_sets: &[u32],
 ) {
-    // Add <Label> and <Role> elements to audio AdaptationSets in the MPD manifest.
+    // Add <Label>, <Role> and <Accessibility> elements to audio AdaptationSets in the MPD manifest.
     // This enables DASH players to distinguish audio tracks, especially when
     // multiple tracks share the same language (e.g., "English" vs "English - Director's Commentary")
     // or have no metadata at all.
-    if audio_info.len() <= 1 && audio_info.iter().all(|(_, _, title)| title.is_empty()) {
-        // Single audio stream without title - no need to post-process
+    if audio_info.is_empty() && hls_only_sets.is_empty() {
         return;
     }
 
-    let mpd_content = match fs::read_to_string(mpd_path) {
-        Ok(content) => content,
-        Err(e) => {
-            eprintln!("Warning: Could not read MPD for post-processing: {}", e);
-            return;
+    let mut mpd = match manifest::Mpd::load(mpd_path) {
+        Ok(mpd) => mpd,
+        Err(e) => {
+            eprintln!("Warning: Could not read MPD for post-processing: {}", e);
+            return;
+        }
+    };
+
+    // AAC renditions for HLS are packaged alongside the others but the MPD keeps only the primary codec
+    for id in hls_only_sets {
+        mpd.remove_adaptation_set(&id.to_string());
+    }
+
+    let labels = compute_audio_labels(audio_info);
+    let mut audio_sets = mpd.adaptation_sets_of_mut("audio");
+
+    // Verify all expected audio tracks were found in the MPD
+    if audio_sets.len() != audio_info.len() {
+        eprintln!(
+            "WARNING: MPD audio track count mismatch! Expected {} audio AdaptationSets but found {} in MPD. Some audio tracks may be missing.",
+            audio_info.len(),
+            audio_sets.len()
+        );
+    }
+
+    let mut labelled = 0;
+    for (idx, (adaptation_set, (_, language, _, _, disposition))) in audio_sets.iter_mut().zip(audio_info).enumerate() {
+        let (roles, accessibility, _) = audio_track_roles(disposition, idx == default_audio, audio_info.len());
+        let lang = normalize_language_code(language);
+        manifest::describe_adaptation_set(adaptation_set, &labels[idx], lang.as_deref(), &roles, &accessibility);
+        labelled += 1;
+    }
+
+    if let Err(e) = mpd.save(mpd_path) {
+        eprintln!("Warning: Could not write post-processed MPD: {}", e);
+    } else {
+        println!("Post-processed MPD with {} audio label(s): {:?}, default track {}", labelled, labels, default_audio);
+    }
+}
+
+/// Check that a referenced package file exists and is non-empty.
+fn check_package_file(path: &str, errors: &mut Vec<String>) {
+    match fs::metadata(path) {
+        Ok(m) if m.len() > 0 => {}
+        Ok(_) => errors.push(format!("{} is empty", path)),
+        Err(_) => errors.push(format!("{} is missing", path)),
+    }
+}
+
+/// Verify a packaged DASH/HLS directory before it is published: every init and media
+/// segment and every side-loaded caption referenced from `video.mpd` and the HLS playlists
+/// exists and is non-empty, every rendition lasts as long as the source (within `tolerance`
+/// seconds) and the MPD has one audio AdaptationSet per expected audio rendition. Returns
+/// all problems found.
+fn validate_packaged_output(dash_dir: &str, source_duration: f64, expected_audio: usize, tolerance: f64) -> Result<(), Vec<String>> {
+    let mut errors: Vec<String> = Vec::new();
+    let duration_ok = |d: f64| source_duration <= 0.0 || (d - source_duration).abs() <= tolerance;
+
+    // DASH
+    let mpd_path = format!("{}/video.mpd", dash_dir);
+    match manifest::Mpd::load(&mpd_path) {
+        Ok(mpd) => {
+            let mut audio_sets = 0;
+            for adaptation_set in mpd.adaptation_sets() {
+                let content_type = manifest::adaptation_content_type(adaptation_set);
+                if content_type == "audio" {
+                    audio_sets += 1;
+                }
+                // Side-loaded captions are a single file per Representation
+                if content_type == "text" {
+                    for representation in adaptation_set.elements().filter(|e| e.name == "Representation") {
+                        match representation.child("BaseURL").map(|b| b.text()) {
+                            Some(url) => check_package_file(&format!("{}/{}", dash_dir, url.trim()), &mut errors),
+                            None => errors.push(format!("MPD text representation {} has no BaseURL", representation.attr("id").unwrap_or(""))),
+                        }
+                    }
+                    continue;
+                }
+                // Trick-play sprites have no segment timeline
+                if content_type == "image" {
+                    continue;
+                }
+                for representation in adaptation_set.elements().filter(|e| e.name == "Representation") {
+                    let representation_id = representation.attr("id").unwrap_or("");
+                    let label = format!("MPD representation {}", representation_id);
+                    // ffmpeg writes the SegmentTemplate per Representation, the schema also allows it on the set
+                    let Some(template) = representation.child("SegmentTemplate").or_else(|| adaptation_set.child("SegmentTemplate")) else {
+                        errors.push(format!("{} has no SegmentTemplate", label));
+                        continue;
+                    };
+                    let timeline: Vec<(u64, u64)> = template
+                        .child("SegmentTimeline")
+                        .map(|t| {
+                            t.elements()
+                                .filter(|s| s.name == "S")
+                                .map(|s| {
+                                    let d = s.attr("d").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+                                    let r = s.attr("r").and_then(|v| v.parse::<i64>().ok()).unwrap_or(0);
+                                    (d, r.max(0) as u64)
+                                })
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    if timeline.is_empty() {
+                        errors.push(format!("{} has no SegmentTimeline", label));
+                        continue;
+                    }
+                    let with_id = |pattern: &str| pattern.replace("$RepresentationID$", representation_id);
+                    if let Some(init) = template.attr("initialization") {
+                        check_package_file(&format!("{}/{}", dash_dir, with_id(init)), &mut errors);
+                    }
+                    let timescale = template.attr("timescale").and_then(|v| v.parse::<f64>().ok()).unwrap_or(1.0).max(1.0);
+                    let start_number = template.attr("startNumber").and_then(|v| v.parse::<u64>().ok()).unwrap_or(1);
+                    let media = template.attr("media").unwrap_or("");
+                    let segment_count: u64 = timeline.iter().map(|(_, r)| r + 1).sum();
+                    for number in start_number..start_number + segment_count {
+                        check_package_file(&format!("{}/{}", dash_dir, with_id(media).replace("$Number$", &number.to_string())), &mut errors);
+                    }
+                    let total = timeline.iter().map(|(d, r)| d * (r + 1)).sum::<u64>() as f64 / timescale;
+                    if !duration_ok(total) {
+                        errors.push(format!("{} lasts {:.2}s, source is {:.2}s", label, total, source_duration));
+                    }
+                }
+            }
+            if audio_sets != expected_audio {
+                errors.push(format!("MPD has {} audio AdaptationSet(s), expected {}", audio_sets, expected_audio));
+            }
+        }
+        Err(e) => errors.push(e),
+    }
+
+    // HLS: master playlist, then every media playlist it references
+    let master_path = format!("{}/video.m3u8", dash_dir);
+    match manifest::Playlist::load(&master_path) {
+        Ok(master) => {
+            let playlists = master.referenced_uris();
+            if playlists.is_empty() {
+                errors.push("HLS master playlist references no media playlists".to_string());
+            }
+            for playlist in playlists {
+                let playlist_path = format!("{}/{}", dash_dir, playlist);
+                let media_playlist = match manifest::Playlist::load(&playlist_path) {
+                    Ok(p) => p,
+                    Err(_) => {
+                        errors.push(format!("{} is missing", playlist_path));
+                        continue;
+                    }
+                };
+                // Segment URIs are relative to the media playlist, which may sit in a subdirectory
+                let playlist_dir = std::path::Path::new(&playlist_path)
+                    .parent()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_else(|| dash_dir.to_string());
+                for uri in media_playlist.referenced_uris() {
+                    check_package_file(&format!("{}/{}", playlist_dir, uri), &mut errors);
+                }
+                let total = media_playlist.total_duration();
+                if !media_playlist.has_raw("#EXT-X-IMAGES-ONLY") && !duration_ok(total) {
+                    errors.push(format!("HLS playlist {} lasts {:.2}s, source is {:.2}s", playlist, total, source_duration));
+                }
+            }
+        }
+        Err(e) => errors.push(e),
+    }
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/// Run `validate_packaged_output` if enabled, recording the result under `validation`
+/// in metadata.json.
+fn check_package(output_dir: &str, dash_dir: &str, duration: f64, expected_audio: usize, dash_config: &DashConfig) -> Result<(), ffmpeg_next::Error> {
+    if !dash_config.validate_output {
+        return Ok(());
+    }
+    match validate_packaged_output(dash_dir, duration, expected_audio, dash_config.duration_tolerance) {
+        Ok(()) => {
+            println!("Packaged output validated");
+            write_metadata_entry(output_dir, "validation", json!({ "ok": true }));
+            Ok(())
+        }
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("Package validation: {}", error);
+            }
+            let shown: Vec<&String> = errors.iter().take(20).collect();
+            write_metadata_entry(output_dir, "validation", json!({ "ok": false, "errors": shown, "error_count": errors.len() }));
+            Err(ffmpeg_next::Error::External)
+        }
+    }
+}
+
+/// Expose the preview sprites as trick-play tracks: a DASH-IF thumbnail AdaptationSet
+/// (`contentType="image"` with a `thumbnail_tile` grid) in the MPD and an
+/// `EXT-X-IMAGE-STREAM-INF` image playlist in the HLS master. Each sprite file is one
+/// segment covering `cols * rows` thumbnails, so the grid has to be full for every
+/// file but the last. The last file usually has fewer rows: HLS gets its real layout,
+/// while the MPD grid applies to the whole Representation and the shorter last segment
+/// keeps players within the rows that exist.
+fn add_trick_play_tracks(dash_output_dir: &str, preview_output_dir: &str, num_sprite_files: u32, num_thumbnails: u32, sprite_config: &PreviewSpriteConfig) {
+    let cols = sprite_config.sprites_across.max(1);
+    let per_file = sprite_config.max_sprites_per_file.max(1);
+    if num_sprite_files > 1 && !per_file.is_multiple_of(cols) {
+        eprintln!(
+            "Warning: max_sprites_per_file ({}) is not a multiple of sprites_across ({}), skipping trick-play tracks",
+            per_file, cols
+        );
+        return;
+    }
+    let rows = per_file.min(num_thumbnails).div_ceil(cols).max(1);
+    let tile_duration = sprite_config.interval_seconds;
+    let segment_duration = (cols * rows) as f64 * tile_duration;
+    let image_width = cols * sprite_config.thumb_width;
+    let image_height = rows * sprite_config.thumb_height;
+
+    // Average bitrate of the sprite files over the covered duration
+    let total_bytes: u64 = (0..num_sprite_files)
+        .filter_map(|i| fs::metadata(format!("{}/preview_sprite_{}.avif", preview_output_dir, i)).ok())
+        .map(|m| m.len())
+        .sum();
+    let covered = (num_thumbnails as f64 * tile_duration).max(1.0);
+    let bandwidth = ((total_bytes as f64 * 8.0) / covered).ceil().max(1.0) as u64;
+
+    // DASH: thumbnail AdaptationSet at the end of the Period
+    let mpd_path = format!("{}/video.mpd", dash_output_dir);
+    match manifest::Mpd::load(&mpd_path) {
+        Ok(mut mpd) => {
+            let adaptation_set = manifest::XmlElement::new("AdaptationSet")
+                .with_attr("id", &mpd.next_adaptation_set_id().to_string())
+                .with_attr("contentType", "image")
+                .with_attr("mimeType", "image/avif")
+                .with_child(
+                    manifest::XmlElement::new("SegmentTemplate")
+                        .with_attr("media", "../previews/preview_sprite_$Number$.avif")
+                        .with_attr("timescale", "1000")
+                        .with_attr("duration", &((segment_duration * 1000.0).round() as u64).to_string())
+                        .with_attr("startNumber", "0"),
+                )
+                .with_child(
+                    manifest::XmlElement::new("Representation")
+                        .with_attr("id", "thumbnails")
+                        .with_attr("bandwidth", &bandwidth.to_string())
+                        .with_attr("width", &image_width.to_string())
+                        .with_attr("height", &image_height.to_string())
+                        .with_child(
+                            manifest::XmlElement::new("EssentialProperty")
+                                .with_attr("schemeIdUri", "http://dashif.org/thumbnail_tile")
+                                .with_attr("value", &format!("{}x{}", cols, rows)),
+                        ),
+                );
+            if let Err(e) = mpd.push_adaptation_set(adaptation_set).and_then(|_| mpd.save(&mpd_path)) {
+                eprintln!("Warning: Could not write MPD with thumbnail track: {}", e);
+            }
+        }
+        Err(e) => eprintln!("Warning: Could not read MPD to add thumbnail track: {}", e),
+    }
+
+    // HLS: image media playlist plus EXT-X-IMAGE-STREAM-INF in the master
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-IMAGES-ONLY\n",
+        segment_duration.ceil() as u64
+    );
+    for i in 0..num_sprite_files {
+        let thumbs = (num_thumbnails - i * per_file).min(per_file);
+        let file_rows = thumbs.div_ceil(cols).max(1);
+        playlist.push_str(&format!(
+            "#EXTINF:{:.3},\n#EXT-X-TILES:RESOLUTION={}x{},LAYOUT={}x{},DURATION={:.3}\n../previews/preview_sprite_{}.avif\n",
+            thumbs as f64 * tile_duration, sprite_config.thumb_width, sprite_config.thumb_height, cols, file_rows, tile_duration, i
+        ));
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    if let Err(e) = fs::write(format!("{}/thumbnails.m3u8", dash_output_dir), playlist) {
+        eprintln!("Warning: Could not write HLS thumbnail playlist: {}", e);
+        return;
+    }
+
+    let m3u8_path = format!("{}/video.m3u8", dash_output_dir);
+    match manifest::Playlist::load(&m3u8_path) {
+        Ok(mut master) => {
+            let mut image_stream = manifest::HlsLine::tag("EXT-X-IMAGE-STREAM-INF");
+            image_stream.set_plain("BANDWIDTH", &bandwidth.to_string());
+            image_stream.set_plain("RESOLUTION", &format!("{}x{}", sprite_config.thumb_width, sprite_config.thumb_height));
+            image_stream.set_quoted("CODECS", "avif");
+            image_stream.set_quoted("URI", "thumbnails.m3u8");
+            master.lines.push(image_stream);
+            if let Err(e) = master.save(&m3u8_path) {
+                eprintln!("Warning: Could not write HLS master playlist with thumbnail track: {}", e);
+            }
+        }
+        Err(e) => eprintln!("Warning: Could not read HLS master playlist to add thumbnail track: {}", e),
+    }
+    println!("Added trick-play thumbnail tracks ({}x{} tiles, {} segment(s))", cols, rows, num_sprite_files);
+}
+
+/// Split a WebVTT file into `segment_seconds` long segments for an HLS subtitle playlist.
+/// Cues are repeated in every segment they overlap, as the HLS spec asks; the
+/// timestamp map pins cue times to the media timeline, which starts at zero.
+fn segment_vtt(vtt_content: &str, duration: f64, segment_seconds: f64) -> Vec<String> {
+    let cues: Vec<(f64, f64, VttCue)> = parse_vtt_cues(vtt_content)
+        .into_iter()
+        .filter_map(|cue| {
+            let start = parse_vtt_timestamp(&cue.start)?;
+            // The end field may carry cue settings ("00:01.000 align:start")
+            let end = parse_vtt_timestamp(cue.end.split_whitespace().next()?)?;
+            Some((start, end, cue))
+        })
+        .collect();
+    let last_cue_end = cues.iter().map(|(_, end, _)| *end).fold(0.0, f64::max);
+    let count = (duration.max(last_cue_end) / segment_seconds).ceil().max(1.0) as usize;
+
+    (0..count)
+        .map(|n| {
+            let (from, to) = (n as f64 * segment_seconds, (n + 1) as f64 * segment_seconds);
+            let mut segment = String::from("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:0,LOCAL:00:00:00.000\n\n");
+            for (_, _, cue) in cues.iter().filter(|(start, end, _)| *start < to && *end > from) {
+                segment.push_str(&format!("{} --> {}\n{}\n\n", cue.start, cue.end, cue.text));
+            }
+            segment
+        })
+        .collect()
+}
+
+/// The caption tracks listed in `captions/list.txt` that have a WebVTT file, as
+/// (name, label, language, default, forced). At most one track is marked default.
+fn caption_tracks(output_dir: &str) -> Vec<(String, String, Option<String>, bool, bool)> {
+    let captions_dir = format!("{}/captions", output_dir);
+    let described = read_metadata_entry(output_dir, "subtitles")
+        .and_then(|v| v.as_array().cloned())
+        .unwrap_or_default();
+    let names: Vec<String> = fs::read_to_string(format!("{}/list.txt", captions_dir))
+        .unwrap_or_default()
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(|l| l.trim_end_matches(".vtt").trim_end_matches(".ass").to_string())
+        .filter(|name| std::path::Path::new(&format!("{}/{}.vtt", captions_dir, name)).exists())
+        .collect();
+
+    let mut tracks: Vec<(String, String, Option<String>, bool, bool)> = Vec::new();
+    for name in names {
+        let entry = described.iter().find(|t| t["name"].as_str() == Some(name.as_str()));
+        let language = entry
+            .and_then(|t| t["language"].as_str())
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .or_else(|| name.strip_prefix("AI_").and_then(normalize_language_code))
+            .or_else(|| normalize_language_code(&name));
+        let title = entry.and_then(|t| t["title"].as_str()).unwrap_or("");
+        let label = if !title.is_empty() {
+            title.to_string()
+        } else {
+            language.as_deref().and_then(language_display_name).unwrap_or_else(|| name.clone())
+        };
+        let default = entry.and_then(|t| t["default"].as_bool()).unwrap_or(false);
+        let forced = entry.and_then(|t| t["forced"].as_bool()).unwrap_or(false);
+        tracks.push((name, label, language, default, forced));
+    }
+    // HLS allows a single DEFAULT=YES per group
+    if let Some(first_default) = tracks.iter().position(|t| t.3) {
+        for (i, track) in tracks.iter_mut().enumerate() {
+            track.3 = i == first_default;
+        }
+    }
+    tracks
+}
+
+/// Subtitle playlist directories in the package, alternated on every publish.
+const SUBTITLE_SLOTS: [&str; 2] = ["subtitles.a", "subtitles.b"];
+
+/// Publish the caption files listed in `captions/list.txt` as text tracks of the packaged
+/// video: one `text/vtt` AdaptationSet per track in `video.mpd` and an `EXT-X-MEDIA`
+/// SUBTITLES group with segmented WebVTT playlists in `video.m3u8`. ASS tracks are published
+/// through their WebVTT fallback. Language, name, default and forced flags come from the
+/// `subtitles` entry in metadata.json where the track was extracted from the source, or from
+/// the file name (`AI_<lang>`) for Whisper and translated tracks. Earlier text tracks are
+/// replaced, so this can run again whenever a caption is added: the new playlists are written
+/// next to the live ones, the manifests are switched, and only then are the old ones removed.
+fn publish_text_tracks(output_dir: &str, dash_config: &DashConfig) {
+    let dash_dir = format!("{}/video", output_dir);
+    let captions_dir = format!("{}/captions", output_dir);
+    let mpd_path = format!("{}/video.mpd", dash_dir);
+    let m3u8_path = format!("{}/video.m3u8", dash_dir);
+
+    let mut mpd = match manifest::Mpd::load(&mpd_path) {
+        Ok(mpd) => mpd,
+        Err(e) => {
+            eprintln!("Warning: Could not read MPD to add text tracks: {}", e);
+            return;
+        }
+    };
+    let mut master = match manifest::Playlist::load(&m3u8_path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Warning: Could not read HLS master playlist to add text tracks: {}", e);
+            return;
+        }
+    };
+
+    // Kept to put the previous text tracks back if the result does not validate
+    let previous_mpd = fs::read_to_string(&mpd_path).unwrap_or_default();
+    let previous_master = master.to_string();
+
+    let tracks = caption_tracks(output_dir);
+
+    let duration = mpd.presentation_duration().unwrap_or(0.0);
+    let segment_seconds = segment_duration_seconds(dash_config);
+    // The playlists go to whichever subtitle directory the live master does not use, so players
+    // keep the old ones until the manifests are switched over
+    let live_slot = master
+        .lines
+        .iter()
+        .filter(|l| l.is_tag("EXT-X-MEDIA") && l.attr("TYPE") == Some("SUBTITLES"))
+        .find_map(|l| l.attr("URI").and_then(|uri| uri.split_once('/')).map(|(dir, _)| dir.to_string()));
+    let slot = SUBTITLE_SLOTS.into_iter().find(|s| live_slot.as_deref() != Some(*s)).unwrap_or(SUBTITLE_SLOTS[0]);
+    let subtitles_dir = format!("{}/{}", dash_dir, slot);
+    let _ = fs::remove_dir_all(&subtitles_dir);
+    if !tracks.is_empty() {
+        if let Err(e) = fs::create_dir_all(&subtitles_dir) {
+            eprintln!("Warning: Could not create subtitle playlist directory: {}", e);
+            return;
+        }
+    }
+
+    mpd.remove_adaptation_sets("text");
+    master.lines.retain(|l| !(l.is_tag("EXT-X-MEDIA") && l.attr("TYPE") == Some("SUBTITLES")));
+
+    let mut media_lines: Vec<manifest::HlsLine> = Vec::new();
+    for (name, label, language, default, forced) in &tracks {
+        let vtt_content = match fs::read_to_string(format!("{}/{}.vtt", captions_dir, name)) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Warning: Could not read caption {}: {}", name, e);
+                continue;
+            }
+        };
+
+        // DASH: side-loaded WebVTT file
+        let roles: Vec<&str> = match (*default, *forced) {
+            (true, true) => vec!["main", "forced-subtitle"],
+            (true, false) => vec!["main", "subtitle"],
+            (false, true) => vec!["forced-subtitle"],
+            (false, false) => vec!["subtitle"],
+        };
+        let mut adaptation_set = manifest::XmlElement::new("AdaptationSet")
+            .with_attr("id", &mpd.next_adaptation_set_id().to_string())
+            .with_attr("contentType", "text")
+            .with_attr("mimeType", "text/vtt")
+            .with_child(
+                manifest::XmlElement::new("Representation")
+                    .with_attr("id", &format!("text_{}", name))
+                    .with_attr("bandwidth", &((vtt_content.len() as f64 * 8.0 / duration.max(1.0)).ceil().max(1.0) as u64).to_string())
+                    .with_child(manifest::XmlElement::new("BaseURL").with_text(&format!("../captions/{}.vtt", name))),
+            );
+        manifest::describe_adaptation_set(&mut adaptation_set, label, language.as_deref(), &roles, &[]);
+        if let Err(e) = mpd.push_adaptation_set(adaptation_set) {
+            eprintln!("Warning: Could not add text track {} to MPD: {}", name, e);
+        }
+
+        // HLS: segmented WebVTT media playlist
+        let segments = segment_vtt(&vtt_content, duration, segment_seconds);
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+            segment_seconds.ceil() as u64
+        );
+        for (n, segment) in segments.iter().enumerate() {
+            let segment_name = format!("{}_{}.vtt", name, n);
+            if let Err(e) = fs::write(format!("{}/{}", subtitles_dir, segment_name), segment) {
+                eprintln!("Warning: Could not write subtitle segment {}: {}", segment_name, e);
+            }
+            let segment_length = if duration > 0.0 { (duration - n as f64 * segment_seconds).min(segment_seconds) } else { segment_seconds };
+            playlist.push_str(&format!("#EXTINF:{:.3},\n{}\n", segment_length.max(0.001), segment_name));
+        }
+        playlist.push_str("#EXT-X-ENDLIST\n");
+        if let Err(e) = fs::write(format!("{}/{}.m3u8", subtitles_dir, name), playlist) {
+            eprintln!("Warning: Could not write subtitle playlist for {}: {}", name, e);
+            continue;
+        }
+
+        let mut media = manifest::HlsLine::tag("EXT-X-MEDIA");
+        media.set_plain("TYPE", "SUBTITLES");
+        media.set_quoted("GROUP-ID", "subs");
+        media.set_quoted("NAME", label);
+        if let Some(lang) = language {
+            media.set_quoted("LANGUAGE", lang);
+        }
+        media.set_plain("DEFAULT", if *default { "YES" } else { "NO" });
+        media.set_plain("AUTOSELECT", "YES");
+        media.set_plain("FORCED", if *forced { "YES" } else { "NO" });
+        media.set_quoted("URI", &format!("{}/{}.m3u8", slot, name));
+        media_lines.push(media);
+    }
+
+    let published = media_lines.len();
+    master.insert_before_variants(media_lines);
+    for variant in master.tags_mut("EXT-X-STREAM-INF") {
+        if published > 0 {
+            variant.set_quoted("SUBTITLES", "subs");
+        } else {
+            variant.remove_attr("SUBTITLES");
+        }
+    }
+
+    if let Err(e) = mpd.save(&mpd_path) {
+        eprintln!("Warning: Could not write MPD with text tracks: {}", e);
+    }
+    if let Err(e) = master.save(&m3u8_path) {
+        eprintln!("Warning: Could not write HLS master playlist with text tracks: {}", e);
+    }
+
+    if dash_config.validate_output {
+        // The audio layout was validated with the package; only the text tracks are new here
+        let audio_sets = mpd.adaptation_sets().filter(|a| manifest::adaptation_content_type(a) == "audio").count();
+        let mut validation = read_metadata_entry(output_dir, "validation").unwrap_or_else(|| json!({}));
+        if let Err(errors) = validate_packaged_output(&dash_dir, duration, audio_sets, dash_config.duration_tolerance) {
+            for error in &errors {
+                eprintln!("Package validation: {}", error);
+            }
+            let shown: Vec<&String> = errors.iter().take(20).collect();
+            validation["text_tracks"] = json!({ "ok": false, "errors": shown, "error_count": errors.len() });
+            write_metadata_entry(output_dir, "validation", validation);
+            if fs::write(&mpd_path, previous_mpd).and_then(|_| fs::write(&m3u8_path, previous_master)).is_err() {
+                eprintln!("Warning: Could not restore the manifests after failed text track validation");
+            }
+            let _ = fs::remove_dir_all(&subtitles_dir);
+            eprintln!("Warning: Text tracks failed validation, kept the previous manifests");
+            return;
+        }
+        validation["text_tracks"] = json!({ "ok": true });
+        write_metadata_entry(output_dir, "validation", validation);
+    }
+
+    // Only now that the manifests point at the new playlists, drop the previous ones
+    for stale in std::iter::once("subtitles").chain(SUBTITLE_SLOTS).filter(|s| *s != slot) {
+        let _ = fs::remove_dir_all(format!("{}/{}", dash_dir, stale));
+    }
+    println!("Published {} text track(s) in the DASH and HLS manifests", published);
+}
+
+/// Per-concept content key, kept in the key file and the `content_keys` table.
+/// KID, key and IV are 16 bytes each, hex-encoded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ContentKey {
+    scheme: EncryptionScheme,
+    kid: String,
+    key: String,
+    iv: String,
+}
+
+/// Everything the packaging step needs to encrypt a concept and signal it in the manifests.
+struct PackageEncryption {
+    key: ContentKey,
+    license_url: String,
+    key_url: String,
+    mp4encrypt_path: String,
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A 32-digit hex KID in the 8-4-4-4-12 UUID form used by `cenc:default_KID`.
+fn kid_uuid(kid: &str) -> String {
+    format!("{}-{}-{}-{}-{}", &kid[0..8], &kid[8..12], &kid[12..16], &kid[16..20], &kid[20..32])
+}
+
+/// Load the concept's content key from `{key_path}/{concept_id}.json`, or generate and store a
+/// new one. Reusing the file keeps the key stable between the preview and full phases and
+/// across retries. The file is created readable by the processor's user only.
+fn load_or_create_content_key(config: &EncryptionConfig, concept_id: &str) -> Result<ContentKey, String> {
+    use std::io::Write;
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+    let key_file = format!("{}/{}.json", config.key_path, concept_id);
+    if let Ok(existing) = fs::read_to_string(&key_file) {
+        match serde_json::from_str::<ContentKey>(&existing) {
+            Ok(key) if key.scheme == config.scheme => return Ok(key),
+            Ok(_) => println!("Content key for {} uses another scheme, generating a new one", concept_id),
+            Err(e) => eprintln!("Warning: Ignoring unreadable key file {}: {}", key_file, e),
+        }
+    }
+
+    // rand's thread RNG is a CSPRNG seeded from the OS
+    let key = ContentKey {
+        scheme: config.scheme,
+        kid: hex_encode(&rand::random::<[u8; 16]>()),
+        key: hex_encode(&rand::random::<[u8; 16]>()),
+        iv: hex_encode(&rand::random::<[u8; 16]>()),
+    };
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&config.key_path)
+        .map_err(|e| format!("Failed to create key directory {}: {}", config.key_path, e))?;
+    let json = serde_json::to_string_pretty(&key).map_err(|e| format!("Failed to serialize content key: {}", e))?;
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&key_file)
+        .and_then(|mut f| f.write_all(json.as_bytes()))
+        .map_err(|e| format!("Failed to write key file {}: {}", key_file, e))?;
+    Ok(key)
+}
+
+/// Encrypt every packaged init and media segment in `dash_dir` in place with the cbcs scheme,
+/// using Bento4 `mp4encrypt`. ffmpeg writes one track (ID 1) per representation, and media
+/// segments are encrypted against their representation's clear init segment.
+fn encrypt_segments_cbcs(dash_dir: &str, encryption: &PackageEncryption) -> Result<(), String> {
+    let key = &encryption.key;
+    let mut entries: Vec<String> = fs::read_dir(dash_dir)
+        .map_err(|e| format!("Cannot read {}: {}", dash_dir, e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect();
+    entries.sort();
+
+    let mp4encrypt = |input: &str, fragments_info: Option<&str>| -> Result<(), String> {
+        let output = format!("{}.enc", input);
+        let mut cmd = Command::new(&encryption.mp4encrypt_path);
+        cmd.arg("--method").arg("MPEG-CBCS")
+            .arg("--key").arg(format!("1:{}:{}", key.key, key.iv))
+            .arg("--property").arg(format!("1:KID:{}", key.kid));
+        if let Some(init) = fragments_info {
+            cmd.arg("--fragments-info").arg(init);
+        }
+        let status = cmd.arg(input).arg(&output).status()
+            .map_err(|e| format!("Failed to run {}: {}", encryption.mp4encrypt_path, e))?;
+        if !status.success() {
+            let _ = fs::remove_file(&output);
+            return Err(format!("mp4encrypt failed for {} with exit code {:?}", input, status.code()));
+        }
+        fs::rename(&output, input).map_err(|e| format!("Failed to replace {}: {}", input, e))
+    };
+
+    let mut encrypted = 0;
+    for init_name in entries.iter().filter(|n| n.starts_with("init_") && n.ends_with(".mp4")) {
+        let representation_id = &init_name["init_".len()..init_name.len() - ".mp4".len()];
+        let init = format!("{}/{}", dash_dir, init_name);
+        let chunk_prefix = format!("chunk_{}_", representation_id);
+        for chunk in entries.iter().filter(|n| n.starts_with(&chunk_prefix) && n.ends_with(".m4s")) {
+            mp4encrypt(&format!("{}/{}", dash_dir, chunk), Some(&init))?;
+            encrypted += 1;
+        }
+        // The init segment last: the media segments above need its clear form
+        mp4encrypt(&init, None)?;
+        encrypted += 1;
+    }
+    println!("Encrypted {} segment(s) with cbcs", encrypted);
+    Ok(())
+}
+
+/// Signal the encryption in the packaged manifests: ContentProtection (mp4protection and
+/// ClearKey) on every video and audio AdaptationSet of `video.mpd`, and `EXT-X-KEY` in every
+/// HLS media playlist plus `EXT-X-SESSION-KEY` in the master playlist.
+fn signal_encryption(dash_dir: &str, encryption: &PackageEncryption) -> Result<(), String> {
+    let key = &encryption.key;
+    let scheme = key.scheme.as_str();
+    let default_kid = kid_uuid(&key.kid);
+
+    let mpd_path = format!("{}/video.mpd", dash_dir);
+    let mut mpd = manifest::Mpd::load(&mpd_path)?;
+    mpd.root.set_attr("xmlns:cenc", "urn:mpeg:cenc:2013");
+    mpd.root.set_attr("xmlns:clearkey", "http://dashif.org/guidelines/clearKey");
+    let license_url = Some(encryption.license_url.as_str()).filter(|u| !u.is_empty());
+    for adaptation_set in mpd.adaptation_sets_mut() {
+        let content_type = manifest::adaptation_content_type(adaptation_set);
+        if content_type == "video" || content_type == "audio" {
+            manifest::protect_adaptation_set(adaptation_set, scheme, &default_kid, license_url);
         }
+    }
+    mpd.save(&mpd_path)?;
+
+    if encryption.key_url.is_empty() {
+        eprintln!("Warning: encryption.key_url is not set, HLS playlists carry no EXT-X-KEY");
+        return Ok(());
+    }
+    // fMP4 HLS carries cenc as SAMPLE-AES-CTR and cbcs as SAMPLE-AES; with the "identity"
+    // key format the key is fetched from URI as 16 raw bytes
+    let key_tag = |name: &str| {
+        let mut tag = manifest::HlsLine::tag(name);
+        tag.set_plain("METHOD", if key.scheme == EncryptionScheme::Cenc { "SAMPLE-AES-CTR" } else { "SAMPLE-AES" });
+        tag.set_quoted("URI", &encryption.key_url);
+        tag.set_quoted("KEYFORMAT", "identity");
+        tag.set_quoted("KEYFORMATVERSIONS", "1");
+        tag
     };
+    let master_path = format!("{}/video.m3u8", dash_dir);
+    let mut master = manifest::Playlist::load(&master_path)?;
+    let mut playlists = master.referenced_uris();
+    playlists.sort();
+    playlists.dedup();
+    for playlist in playlists {
+        let playlist_path = format!("{}/{}", dash_dir, playlist);
+        let mut media_playlist = manifest::Playlist::load(&playlist_path)?;
+        media_playlist.lines.retain(|l| !l.is_tag("EXT-X-KEY"));
+        media_playlist.insert_before_tag("EXT-X-MAP", vec![key_tag("EXT-X-KEY")]);
+        media_playlist.save(&playlist_path)?;
+    }
+    master.lines.retain(|l| !l.is_tag("EXT-X-SESSION-KEY"));
+    master.insert_before_variants(vec![key_tag("EXT-X-SESSION-KEY")]);
+    master.save(&master_path)?;
+    Ok(())
+}
 
-    let labels = compute_audio_labels(audio_info);
+/// Write the progressive `{output_dir}/download.mp4` and return its size. Video comes from
+/// `video_input`: the source through `video_filter` for an H.264 encode, or the top rendition
+/// stream-copied in remux mode. Each `audio` entry (file, language, title) becomes one audio
+/// track with its language tag; `default_audio` is flagged as the default track. The moov atom
+/// is moved to the front so the file plays while downloading.
+fn build_download(
+    output_dir: &str,
+    video_input: &str,
+    video_filter: Option<&str>,
+    audio: &[(String, String, String)],
+    default_audio: usize,
+    config: &DownloadConfig,
+    fps_cap: f32,
+) -> Option<u64> {
+    let download_path = format!("{}/download.mp4", output_dir);
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args(["-nostdin", "-y", "-analyzeduration", "1000M", "-probesize", "1000M", "-i", video_input]);
+    for (file, ..) in audio {
+        cmd.args(["-i", file]);
+    }
+    cmd.args(["-map", "0:v:0"]);
+    for i in 0..audio.len() {
+        cmd.args(["-map", &format!("{}:a:0", i + 1)]);
+    }
+    match config.mode {
+        DownloadMode::H264 => {
+            if let Some(filter) = video_filter {
+                cmd.args(["-vf", filter]);
+            }
+            cmd.args(["-c:v", "libx264", "-preset", &config.preset, "-crf", &config.crf.to_string()])
+                .args(["-profile:v", "high", "-pix_fmt", "yuv420p", "-fpsmax", &fps_cap.to_string()])
+                .args(["-c:a", "aac", "-b:a", &format!("{}k", config.audio_bitrate)]);
+        }
+        DownloadMode::Remux => {
+            cmd.args(["-c", "copy"]);
+        }
+    }
+    for (i, (_, language, title)) in audio.iter().enumerate() {
+        let name = if title.is_empty() { language_display_name(language) } else { Some(title.clone()) };
+        if let Some(code) = container_language_code(language) {
+            cmd.args([format!("-metadata:s:a:{}", i), format!("language={}", code)]);
+        }
+        if let Some(name) = name {
+            cmd.args([format!("-metadata:s:a:{}", i), format!("title={}", name)]);
+            cmd.args([format!("-metadata:s:a:{}", i), format!("handler_name={}", name)]);
+        }
+        cmd.args([format!("-disposition:a:{}", i), if i == default_audio { "default" } else { "0" }.to_string()]);
+    }
+    cmd.args(["-map_metadata", "-1", "-movflags", "+faststart", &download_path]);
 
-    let mut result = String::with_capacity(mpd_content.len() + 512);
-    let mut audio_adaptation_idx = 0;
-
-    for line in mpd_content.lines() {
-        result.push_str(line);
-        result.push('\n');
-
-        // Detect audio AdaptationSet opening tags
-        if line.contains("<AdaptationSet") && line.contains("contentType=\"audio\"") {
-            if audio_adaptation_idx < audio_info.len() {
-                let (_, _, title) = &audio_info[audio_adaptation_idx];
-                let label = &labels[audio_adaptation_idx];
-
-                // Detect indentation from the AdaptationSet line
-                let indent = &line[..line.len() - line.trim_start().len()];
-                let child_indent = format!("{}  ", indent);
-
-                // Add <Label> element
-                result.push_str(&format!("{}<Label>{}</Label>\n", child_indent, label));
-
-                // Add <Role> element for commentary tracks
-                let title_lower = title.to_lowercase();
-                if title_lower.contains("commentary") || title_lower.contains("komentář") {
-                    result.push_str(&format!(
-                        "{}<Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"commentary\"/>\n",
-                        child_indent
-                    ));
-                } else if audio_info.len() > 1 && audio_adaptation_idx == 0 {
-                    // Mark the first audio track as "main" when there are multiple tracks
-                    result.push_str(&format!(
-                        "{}<Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"main\"/>\n",
-                        child_indent
-                    ));
-                }
+    println!("Building {:?} download: {}", config.mode, download_path);
+    match cmd.status() {
+        Ok(status) if status.success() => fs::metadata(&download_path).map(|m| m.len()).ok(),
+        Ok(status) => {
+            eprintln!("Warning: Download build failed with exit code: {:?}", status.code());
+            let _ = fs::remove_file(&download_path);
+            None
+        }
+        Err(e) => {
+            eprintln!("Warning: Failed to execute ffmpeg for the download: {}", e);
+            None
+        }
+    }
+}
 
-                audio_adaptation_idx += 1;
-            }
+/// Remux `download.mp4` with the published captions as mov_text soft subtitles, replacing any
+/// embedded earlier, and update the `download` entry in metadata.json. Does nothing when no
+/// download exists or it was built without subtitles.
+fn embed_download_subtitles(output_dir: &str) {
+    let download_path = format!("{}/download.mp4", output_dir);
+    let mut entry = match read_metadata_entry(output_dir, "download") {
+        Some(entry) if entry["subtitles"].is_array() && std::path::Path::new(&download_path).exists() => entry,
+        _ => return,
+    };
+    let tracks = caption_tracks(output_dir);
+    let temp_path = format!("{}/download.tmp.mp4", output_dir);
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args(["-nostdin", "-y", "-i", &download_path]);
+    for (name, ..) in &tracks {
+        cmd.args(["-i", &format!("{}/captions/{}.vtt", output_dir, name)]);
+    }
+    cmd.args(["-map", "0:v", "-map", "0:a?"]);
+    for i in 0..tracks.len() {
+        cmd.args(["-map", &format!("{}:0", i + 1)]);
+    }
+    cmd.args(["-c", "copy", "-c:s", "mov_text"]);
+    for (i, (_, label, language, default, forced)) in tracks.iter().enumerate() {
+        if let Some(code) = language.as_deref().and_then(container_language_code) {
+            cmd.args([format!("-metadata:s:s:{}", i), format!("language={}", code)]);
         }
+        cmd.args([format!("-metadata:s:s:{}", i), format!("handler_name={}", label)]);
+        let disposition = match (default, forced) {
+            (true, true) => "default+forced",
+            (true, false) => "default",
+            (false, true) => "forced",
+            (false, false) => "0",
+        };
+        cmd.args([format!("-disposition:s:{}", i), disposition.to_string()]);
     }
+    cmd.args(["-movflags", "+faststart", &temp_path]);
 
-    // Verify all expected audio tracks were found in the MPD
-    if audio_adaptation_idx != audio_info.len() {
-        eprintln!(
-            "WARNING: MPD audio track count mismatch! Expected {} audio AdaptationSets but found {} in MPD. Some audio tracks may be missing.",
-            audio_info.len(),
-            audio_adaptation_idx
-        );
+    match cmd.status() {
+        Ok(status) if status.success() => {
+            if let Err(e) = fs::rename(&temp_path, &download_path) {
+                eprintln!("Warning: Could not replace download with the subtitled one: {}", e);
+                let _ = fs::remove_file(&temp_path);
+                return;
+            }
+            entry["size"] = json!(fs::metadata(&download_path).map(|m| m.len()).unwrap_or(0));
+            entry["subtitles"] = json!(tracks.iter().map(|(name, ..)| name.as_str()).collect::<Vec<_>>());
+            write_metadata_entry(output_dir, "download", entry);
+            println!("Embedded {} subtitle track(s) in the download", tracks.len());
+        }
+        _ => {
+            eprintln!("Warning: Could not embed subtitles in the download");
+            let _ = fs::remove_file(&temp_path);
+        }
     }
+}
 
-    if let Err(e) = fs::write(mpd_path, result) {
-        eprintln!("Warning: Could not write post-processed MPD: {}", e);
-    } else {
-        println!("Post-processed MPD with {} audio label(s): {:?}", audio_adaptation_idx, labels);
+/// Package directories the `video` symlink alternates between with `fast_preview`.
+const VIDEO_SLOTS: [&str; 2] = ["video.a", "video.b"];
+
+/// Point `{output_dir}/video` at `slot` and delete the previously published slot. A new
+/// link is renamed over the old one, so players always see a complete package.
+fn swap_video_slot(output_dir: &str, slot: &str, previous_slot: Option<&str>) -> Result<(), ffmpeg_next::Error> {
+    let link = format!("{}/video", output_dir);
+    let temp_link = format!("{}/video.link", output_dir);
+    // A plain directory left by an earlier run cannot be replaced by a rename
+    if fs::symlink_metadata(&link).map(|m| m.is_dir()).unwrap_or(false) {
+        let _ = fs::remove_dir_all(&link);
+    }
+    let _ = fs::remove_file(&temp_link);
+    std::os::unix::fs::symlink(slot, &temp_link)
+        .and_then(|_| fs::rename(&temp_link, &link))
+        .map_err(|e| {
+            eprintln!("Failed to publish {} as video/: {}", slot, e);
+            ffmpeg_next::Error::External
+        })?;
+    if let Some(previous) = previous_slot.filter(|p| *p != slot && VIDEO_SLOTS.contains(p)) {
+        let _ = fs::remove_dir_all(format!("{}/{}", output_dir, previous));
     }
+    Ok(())
 }
 
 async fn transcode_video(
     input_file: &str,
     output_dir: &str,
     config: &VideoConfig,
+    loudness_config: &LoudnessConfig,
+    phase: EncodePhase,
+    encryption: Option<&PackageEncryption>,
 ) -> Result<(), ffmpeg_next::Error> {
     ffmpeg_next::init()?;
 
@@ -4388,8 +7775,8 @@ async fn transcode_video(
     let video_decoder = codec::context::Context::from_parameters(video_stream.parameters())?
         .decoder()
         .video()?;
-    let original_width = video_decoder.width();
-    let original_height = video_decoder.height();
+    let source_width = video_decoder.width();
+    let source_height = video_decoder.height();
     let framerate: f32;
     let fr = video_stream.avg_frame_rate();
     let fps = if fr.denominator() == 0 {
@@ -4405,6 +7792,48 @@ async fn transcode_video(
     }
     let duration = input_context.duration() as f64 / ffmpeg_next::ffi::AV_TIME_BASE as f64; // Video duration in seconds
 
+    // Detect black bars; the cropped area replaces the source size for the whole ladder
+    let crop = if config.crop_detect.enabled {
+        detect_crop(input_file, duration, source_width, source_height, &config.crop_detect)
+    } else {
+        None
+    };
+    let (original_width, original_height) = match crop {
+        Some(c) => {
+            println!("Cropping black bars: {}x{} -> {}x{} at {},{}", source_width, source_height, c.width, c.height, c.x, c.y);
+            write_metadata_entry(output_dir, "crop", json!({
+                "source_width": source_width,
+                "source_height": source_height,
+                "width": c.width,
+                "height": c.height,
+                "x": c.x,
+                "y": c.y,
+            }));
+            (c.width, c.height)
+        }
+        None => (source_width, source_height),
+    };
+    // Prefix for software filter chains: "crop=...," or empty
+    let crop_prefix = crop.map(|c| format!("{},", c.filter())).unwrap_or_default();
+
+    // Logo overlay, already resolved for the concept's owner. Software chains overlay it at
+    // source resolution after crop and tonemapping, so every rung scales it along
+    let watermark = Some(&config.watermark).filter(|wm| wm.active());
+    if config.watermark.enabled && watermark.is_none() {
+        eprintln!("Warning: Watermark image '{}' not found, skipping the overlay", config.watermark.image);
+    }
+    let watermark_sw = watermark
+        .map(|wm| format!("{},", watermark_filter(wm, original_width, OverlayBackend::Software, "")))
+        .unwrap_or_default();
+    let thumb_prefix = match watermark {
+        Some(wm) if wm.thumbnails => format!("{}{}", crop_prefix, watermark_sw),
+        _ => crop_prefix.clone(),
+    };
+    let sprite_prefix = match watermark {
+        Some(wm) if wm.sprites => format!("{}{}", crop_prefix, watermark_sw),
+        _ => crop_prefix.clone(),
+    };
+
     let mut audio_bitrate = config.audio_bitrate_base;
 
     // Calculate aspect ratio once to ensure all resolutions maintain it
@@ -4464,88 +7893,210 @@ async fn transcode_video(
         height = new_height;
     }
 
+    // The preview phase only encodes the cheapest rung
+    if phase == EncodePhase::Preview {
+        outputs = outputs.pop().into_iter().collect();
+    }
+
     println!("Generated {} quality outputs: {:?}", outputs.len(), outputs.iter().map(|(_, _, label)| label.clone()).collect::<Vec<_>>());
 
+    // Audio ladder: with dash.audio_ladder every planned rung brings its own audio bitrate
+    // (base / audio_bitrate_divisor), and HLS pairs each video variant with its rung's audio
+    let rung_audio_bitrate = |label: &str| -> u32 {
+        if !config.dash.audio_ladder {
+            return dash_audio_bitrate;
+        }
+        let divisor = config.quality_steps
+            .iter()
+            .find(|step| step.label == label)
+            .map(|step| step.audio_bitrate_divisor.max(1))
+            .unwrap_or(1);
+        (dash_audio_bitrate / divisor).max(config.dash.audio_ladder_min_bitrate).min(dash_audio_bitrate)
+    };
+    let mut audio_ladder: Vec<u32> = outputs.iter().map(|(_, _, label)| rung_audio_bitrate(label)).collect();
+    audio_ladder.sort_unstable_by(|a, b| b.cmp(a));
+    audio_ladder.dedup();
+    if audio_ladder.is_empty() {
+        audio_ladder.push(dash_audio_bitrate);
+    }
+
     let mut fmp4_files = Vec::new();
-    let dash_output_dir = format!("{}/video", output_dir);
+    // The preview is published through a video/ symlink. The full ladder is packaged into
+    // the other slot while the preview keeps serving, and the link is swapped at the end.
+    let published_slot = fs::read_link(format!("{}/video", output_dir))
+        .ok()
+        .map(|target| target.to_string_lossy().to_string());
+    let slot = if phase == EncodePhase::Preview || published_slot.is_some() {
+        VIDEO_SLOTS.iter().find(|s| published_slot.as_deref() != Some(**s)).copied()
+    } else {
+        None
+    };
+    let dash_output_dir = match slot {
+        Some(slot) => {
+            let slot_dir = format!("{}/{}", output_dir, slot);
+            let _ = fs::remove_dir_all(&slot_dir);
+            slot_dir
+        }
+        None => format!("{}/video", output_dir),
+    };
 
     // Detect HDR characteristics
     let hdr_info = detect_hdr(input_file);
 
-    // Build encoder-specific ffmpeg parameters
-    let (hwaccel_args, codec_params, tonemap_filter, encoder_type) = build_encoder_params(config, framerate, &hdr_info);
+    // Long inputs can be split into keyframe-aligned chunks encoded in parallel
+    let chunks = if config.chunked.enabled && phase == EncodePhase::Full && duration >= config.chunked.min_duration {
+        let input_file_c = input_file.to_string();
+        let chunk_config = config.chunked.clone();
+        task::spawn_blocking(move || plan_encode_chunks(&input_file_c, duration, &chunk_config))
+            .await
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    if !chunks.is_empty() {
+        println!(
+            "Chunked encoding: {} chunks per rendition, parallel_limit={}",
+            chunks.len(), config.chunked.parallel_limit.max(1)
+        );
+    }
 
-    // Transcode each quality level in parallel (video-only; audio is transcoded once separately for DASH)
-    let mut transcode_handles = Vec::new();
-    for (w, h, label) in &outputs {
-        let output_file = format!("{}/output_{}.mp4", output_dir, label);
-        fmp4_files.push(output_file.clone());
+    // Chunks restart their timestamps at zero, so chapter keyframes only apply to whole-file encodes
+    let chapter_starts = if config.dash.force_chapter_keyframes && chunks.is_empty() {
+        probe_chapter_starts(input_file)
+    } else {
+        Vec::new()
+    };
+    let chapter_keyframes = if chapter_starts.is_empty() {
+        Vec::new()
+    } else {
+        println!("Forcing keyframes at {} chapter start(s)", chapter_starts.len());
+        keyframe_schedule(duration, segment_duration_seconds(&config.dash), &chapter_starts)
+    };
+
+    // Stream-copy the top rung when the source already is what we would encode.
+    // Encoded rungs then take their keyframes from the source so all rungs stay aligned.
+    let remux_source_keyframes = match outputs.first() {
+        Some((w, h, _))
+            if config.remux.enabled && phase == EncodePhase::Full && chunks.is_empty() && crop.is_none() && !hdr_info.is_hdr
+                && watermark.is_none()
+                && *w == source_width && *h == source_height =>
+        {
+            let input_file_r = input_file.to_string();
+            let config_r = config.clone();
+            task::spawn_blocking(move || remux_keyframes(&input_file_r, &config_r, framerate, fps))
+                .await
+                .unwrap_or(None)
+        }
+        _ => None,
+    };
+    let remux_top = remux_source_keyframes.is_some();
+    let chapter_keyframes = match remux_source_keyframes {
+        Some(keyframes) => {
+            if let Some((w, h, label)) = outputs.first() {
+                write_metadata_entry(output_dir, "remux", json!({
+                    "rung": label,
+                    "width": w,
+                    "height": h,
+                    "keyframes": keyframes.len(),
+                }));
+            }
+            keyframes
+        }
+        None => chapter_keyframes,
+    };
 
-        let cmd = match encoder_type {
+    // Build encoder-specific ffmpeg parameters
+    let (hwaccel_args, codec_params, tonemap_filter, encoder_type) = build_encoder_params(config, framerate, &hdr_info, &chapter_keyframes);
+
+    // Build the ffmpeg command for one rendition; `input_spec` carries the -i (and any seek) arguments
+    let build_cmd = |w: &u32, h: &u32, input_spec: &str, output_file: &str| -> String {
+        match encoder_type {
             EncoderType::Qsv => {
-                let hwaccel_args = "-hwaccel qsv -hwaccel_output_format qsv";
+                let hwaccel_args = if watermark.is_some() { QSV_OVERLAY_HWACCEL } else { "-hwaccel qsv -hwaccel_output_format qsv" };
+                // vpp_qsv crops in hardware before scaling
+                let vpp_crop = match crop {
+                    Some(c) => format!("cw={}:ch={}:cx={}:cy={}:", c.width, c.height, c.x, c.y),
+                    None => String::new(),
+                };
+                // overlay_qsv places the logo on the scaled hardware frames
+                let overlay = watermark
+                    .map(|wm| format!(",{}", watermark_filter(wm, *w, OverlayBackend::Qsv, "")))
+                    .unwrap_or_default();
 
                 if hdr_info.is_hdr {
                     format!(
-                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} -i '{}' \
-                         -vf 'vpp_qsv=w={}:h={}:tonemap=1:format=p010le:out_color_matrix=bt709' \
+                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} {} \
+                         -vf 'vpp_qsv={}w={}:h={}:tonemap=1:format=p010le:out_color_matrix=bt709{}' \
                          {} -pix_fmt p010le \
                          -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
                         hwaccel_args,
-                        input_file,
+                        input_spec,
+                        vpp_crop,
                         w, h,
+                        overlay,
                         codec_params,
                         output_file
                     )
                 } else {
                     format!(
-                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} -i '{}' \
-                         -vf 'vpp_qsv=w={}:h={}:format=p010le' \
+                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} {} \
+                         -vf 'vpp_qsv={}w={}:h={}:format=p010le{}' \
                          {} -pix_fmt p010le \
                          -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
                         hwaccel_args,
-                        input_file,
+                        input_spec,
+                        vpp_crop,
                         w, h,
+                        overlay,
                         codec_params,
                         output_file
                     )
                 }
             }
             EncoderType::Nvenc => {
-                if hdr_info.is_hdr {
-                    // HDR path: software tonemapping then NVENC encode
+                if hdr_info.is_hdr || crop.is_some() {
+                    // HDR / crop path: software crop and tonemapping then NVENC encode
                     let filter_chain = if tonemap_filter.is_empty() {
-                        format!("scale={}:{}:force_original_aspect_ratio=decrease:finterp=true,format=yuv420p10le", w, h)
+                        format!("{}{}scale={}:{}:force_original_aspect_ratio=decrease:finterp=true,format=yuv420p10le", crop_prefix, watermark_sw, w, h)
                     } else {
-                        format!("{},scale={}:{}:force_original_aspect_ratio=decrease:finterp=true", tonemap_filter, w, h)
+                        format!("{}{},{}scale={}:{}:force_original_aspect_ratio=decrease:finterp=true", crop_prefix, tonemap_filter, watermark_sw, w, h)
                     };
                     format!(
-                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M -init_hw_device cuda=cuda0 -filter_hw_device cuda0 -i '{}' -vf '{}' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
-                        input_file, filter_chain, codec_params, output_file
+                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M -init_hw_device cuda=cuda0 -filter_hw_device cuda0 {} -vf '{}' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
+                        input_spec, filter_chain, codec_params, output_file
                     )
                 } else {
+                    // overlay_cuda places the logo on the scaled CUDA frames
+                    let (hwaccel_args, overlay) = match watermark {
+                        Some(wm) => (CUDA_OVERLAY_HWACCEL, format!(",{}", watermark_filter(wm, *w, OverlayBackend::Cuda, ""))),
+                        None => (hwaccel_args.as_str(), String::new()),
+                    };
                     format!(
-                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} -i '{}' -vf 'scale_cuda={}:{}:force_original_aspect_ratio=decrease:finterp=true' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
-                        hwaccel_args, input_file, w, h, codec_params, output_file
+                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} {} -vf 'scale_cuda={}:{}:force_original_aspect_ratio=decrease:finterp=true{}' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
+                        hwaccel_args, input_spec, w, h, overlay, codec_params, output_file
                     )
                 }
             }
             EncoderType::Vaapi => {
-                if hdr_info.is_hdr {
-                    // HDR path: software tonemapping then VAAPI encode
+                if hdr_info.is_hdr || crop.is_some() {
+                    // HDR / crop path: software crop and tonemapping then VAAPI encode
                     let filter_chain = if tonemap_filter.is_empty() {
-                        format!("scale={}:{}:force_original_aspect_ratio=decrease,format=p010le", w, h)
+                        format!("{}{}scale={}:{}:force_original_aspect_ratio=decrease,format=p010le", crop_prefix, watermark_sw, w, h)
                     } else {
-                        format!("{},scale={}:{}:force_original_aspect_ratio=decrease,format=p010le", tonemap_filter, w, h)
+                        format!("{}{},{}scale={}:{}:force_original_aspect_ratio=decrease,format=p010le", crop_prefix, tonemap_filter, watermark_sw, w, h)
                     };
                     format!(
-                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M -vaapi_device /dev/dri/renderD128 -i '{}' -vf '{}' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
-                        input_file, filter_chain, codec_params, output_file
+                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M -vaapi_device /dev/dri/renderD128 {} -vf '{}' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
+                        input_spec, filter_chain, codec_params, output_file
                     )
                 } else {
+                    // overlay_vaapi places the logo on the scaled VAAPI surfaces
+                    let overlay = watermark
+                        .map(|wm| format!(",{}", watermark_filter(wm, *w, OverlayBackend::Vaapi, "")))
+                        .unwrap_or_default();
                     format!(
-                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} -i '{}' -vf 'scale_vaapi={}:{}:force_original_aspect_ratio=decrease,format=p010le' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
-                        hwaccel_args, input_file, w, h, codec_params, output_file
+                        "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} {} -vf 'scale_vaapi={}:{}:force_original_aspect_ratio=decrease,format=p010le{}' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
+                        hwaccel_args, input_spec, w, h, overlay, codec_params, output_file
                     )
                 }
             }
@@ -4554,52 +8105,305 @@ async fn transcode_video(
                 // then hand off frames to the kernel encoder via V4L2.
                 // Most ARM v4l2m2m drivers only accept yuv420p (8-bit).
                 let filter_chain = if hdr_info.is_hdr && !tonemap_filter.is_empty() {
-                    format!("{},scale={}:{}:force_original_aspect_ratio=decrease,format=yuv420p", tonemap_filter, w, h)
+                    format!("{}{},{}scale={}:{}:force_original_aspect_ratio=decrease,format=yuv420p", crop_prefix, tonemap_filter, watermark_sw, w, h)
                 } else {
-                    format!("scale={}:{}:force_original_aspect_ratio=decrease,format=yuv420p", w, h)
+                    format!("{}{}scale={}:{}:force_original_aspect_ratio=decrease,format=yuv420p", crop_prefix, watermark_sw, w, h)
                 };
                 format!(
-                    "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M -i '{}' -vf '{}' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
-                    input_file, filter_chain, codec_params, output_file
+                    "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} -vf '{}' {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
+                    input_spec, filter_chain, codec_params, output_file
+                )
+            }
+        }
+    };
+
+    // Transcode each quality level in parallel (video-only; audio is transcoded once separately for DASH)
+    // Build one ffmpeg command that decodes (and crops/tonemaps) once, then splits the
+    // graph into one scaling branch and encoder output per rung
+    let build_multi_cmd = |input_spec: &str, rungs: &[(u32, u32, String)]| -> String {
+        let mut shared: Vec<String> = Vec::new();
+        // Software paths overlay the logo once before the split, hardware paths in every branch
+        let sw_overlay = watermark.map(|wm| watermark_filter(wm, original_width, OverlayBackend::Software, ""));
+        let hw_overlay = |backend: OverlayBackend, w: u32, branch: usize| -> String {
+            watermark
+                .map(|wm| format!(",{}", watermark_filter(wm, w, backend, &branch.to_string())))
+                .unwrap_or_default()
+        };
+        let (input_args, extra_output_args, branches): (&str, &str, Vec<String>) = match encoder_type {
+            EncoderType::Qsv => {
+                // vpp_qsv crops and tonemaps in hardware, so every branch keeps its own vpp_qsv
+                let vpp_crop = match crop {
+                    Some(c) => format!("cw={}:ch={}:cx={}:cy={}:", c.width, c.height, c.x, c.y),
+                    None => String::new(),
+                };
+                let vpp_format = if hdr_info.is_hdr { "tonemap=1:format=p010le:out_color_matrix=bt709" } else { "format=p010le" };
+                (
+                    if watermark.is_some() { QSV_OVERLAY_HWACCEL } else { "-hwaccel qsv -hwaccel_output_format qsv" },
+                    "-pix_fmt p010le",
+                    rungs
+                        .iter()
+                        .enumerate()
+                        .map(|(i, (w, h, _))| format!("vpp_qsv={}w={}:h={}:{}{}", vpp_crop, w, h, vpp_format, hw_overlay(OverlayBackend::Qsv, *w, i)))
+                        .collect(),
+                )
+            }
+            EncoderType::Nvenc if hdr_info.is_hdr || crop.is_some() => {
+                shared.extend(crop.map(|c| c.filter()));
+                let branch_suffix = if tonemap_filter.is_empty() {
+                    ",format=yuv420p10le"
+                } else {
+                    shared.push(tonemap_filter.clone());
+                    ""
+                };
+                shared.extend(sw_overlay.clone());
+                (
+                    "-init_hw_device cuda=cuda0 -filter_hw_device cuda0",
+                    "",
+                    rungs.iter().map(|(w, h, _)| format!("scale={}:{}:force_original_aspect_ratio=decrease:finterp=true{}", w, h, branch_suffix)).collect(),
+                )
+            }
+            EncoderType::Nvenc => (
+                if watermark.is_some() { CUDA_OVERLAY_HWACCEL } else { hwaccel_args.as_str() },
+                "",
+                rungs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (w, h, _))| format!("scale_cuda={}:{}:force_original_aspect_ratio=decrease:finterp=true{}", w, h, hw_overlay(OverlayBackend::Cuda, *w, i)))
+                    .collect(),
+            ),
+            EncoderType::Vaapi if hdr_info.is_hdr || crop.is_some() => {
+                shared.extend(crop.map(|c| c.filter()));
+                if !tonemap_filter.is_empty() {
+                    shared.push(tonemap_filter.clone());
+                }
+                shared.extend(sw_overlay.clone());
+                (
+                    "-vaapi_device /dev/dri/renderD128",
+                    "",
+                    rungs.iter().map(|(w, h, _)| format!("scale={}:{}:force_original_aspect_ratio=decrease,format=p010le", w, h)).collect(),
+                )
+            }
+            EncoderType::Vaapi => (
+                hwaccel_args.as_str(),
+                "",
+                rungs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (w, h, _))| format!("scale_vaapi={}:{}:force_original_aspect_ratio=decrease,format=p010le{}", w, h, hw_overlay(OverlayBackend::Vaapi, *w, i)))
+                    .collect(),
+            ),
+            EncoderType::V4l2m2m => {
+                shared.extend(crop.map(|c| c.filter()));
+                if hdr_info.is_hdr && !tonemap_filter.is_empty() {
+                    shared.push(tonemap_filter.clone());
+                }
+                shared.extend(sw_overlay.clone());
+                (
+                    "",
+                    "",
+                    rungs.iter().map(|(w, h, _)| format!("scale={}:{}:force_original_aspect_ratio=decrease,format=yuv420p", w, h)).collect(),
                 )
             }
         };
 
-        let label_owned = label.clone();
-        let output_file_owned = output_file.clone();
-        transcode_handles.push(task::spawn_blocking(move || {
-            println!("Executing: {}", cmd);
-            let status = Command::new("sh")
-                .arg("-c")
-                .arg(&cmd)
-                .status();
-            (status, label_owned, output_file_owned)
+        shared.push(format!(
+            "split={}{}",
+            branches.len(),
+            (0..branches.len()).map(|i| format!("[s{}]", i)).collect::<String>()
+        ));
+        let mut filter_complex = format!("[0:v:0]{}", shared.join(","));
+        let mut output_args = String::new();
+        for (i, (branch, (_, _, file))) in branches.iter().zip(rungs).enumerate() {
+            filter_complex.push_str(&format!(";[s{}]{}[o{}]", i, branch, i));
+            output_args.push_str(&format!(
+                " -map '[o{}]' {} {} -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
+                i, codec_params, extra_output_args, file
+            ));
+        }
+        format!(
+            "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} {} -filter_complex '{}'{}",
+            input_args, input_spec, filter_complex, output_args
+        )
+    };
+
+    // Plan the encode jobs: one per rendition (and chunk), or one per chunk covering
+    // every rung when decoding once in a single process
+    let segments: Vec<(String, Option<(usize, f64)>)> = if chunks.is_empty() {
+        vec![(format!("-i '{}'", input_file), None)]
+    } else {
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(i, (start, length))| (format!("-ss {:.6} -t {:.6} -i '{}'", start, length, input_file), Some((i, *length))))
+            .collect()
+    };
+    let mut chunk_parts: Vec<Vec<(String, f64)>> = vec![Vec::new(); outputs.len()];
+    let mut jobs: Vec<(String, String, Vec<String>)> = Vec::new(); // (label, command, output files)
+    for (input_spec, chunk) in &segments {
+        let mut rungs: Vec<(u32, u32, String)> = Vec::new();
+        for (idx, (w, h, label)) in outputs.iter().enumerate() {
+            if idx == 0 && remux_top {
+                let file = format!("{}/output_{}.mp4", output_dir, label);
+                let cmd = format!(
+                    "ffmpeg -nostdin -y {} -map 0:v:0 -c copy -an -f mp4 -movflags frag_keyframe+empty_moov+default_base_moof '{}'",
+                    input_spec, file
+                );
+                jobs.push((format!("{} (remux)", label), cmd, vec![file]));
+                continue;
+            }
+            let file = match chunk {
+                Some((chunk_idx, length)) => {
+                    let part_file = format!("{}/output_{}_part{:04}.mp4", output_dir, label, chunk_idx);
+                    chunk_parts[idx].push((part_file.clone(), *length));
+                    part_file
+                }
+                None => format!("{}/output_{}.mp4", output_dir, label),
+            };
+            rungs.push((*w, *h, file));
+        }
+        let suffix = chunk.map(|(chunk_idx, _)| format!(" chunk {}", chunk_idx)).unwrap_or_default();
+        if rungs.is_empty() {
+            continue;
+        }
+        if config.single_process {
+            let files = rungs.iter().map(|(_, _, f)| f.clone()).collect();
+            jobs.push((format!("all rungs{}", suffix), build_multi_cmd(input_spec, &rungs), files));
+        } else {
+            let encoded_outputs = outputs.iter().skip(if remux_top { 1 } else { 0 });
+            for ((w, h, file), (_, _, label)) in rungs.iter().zip(encoded_outputs) {
+                jobs.push((format!("{}{}", label, suffix), build_cmd(w, h, input_spec, file), vec![file.clone()]));
+            }
+        }
+    }
+    let mut chunked_outputs = Vec::new();
+    for ((_, _, label), parts) in outputs.iter().zip(chunk_parts) {
+        let output_file = format!("{}/output_{}.mp4", output_dir, label);
+        fmp4_files.push(output_file.clone());
+        if !chunks.is_empty() {
+            chunked_outputs.push((label.clone(), output_file, parts));
+        }
+    }
+
+    // Run the jobs; chunked encodes are throttled to the configured session limit
+    let parallel_limit = if chunks.is_empty() { jobs.len().max(1) } else { config.chunked.parallel_limit.max(1) as usize };
+    let job_semaphore = Arc::new(Semaphore::new(parallel_limit));
+    let retries = config.rendition_policy.retries;
+    let mut transcode_handles = Vec::new();
+    for (label, cmd, output_files) in jobs {
+        let permit = Arc::clone(&job_semaphore);
+        transcode_handles.push(task::spawn(async move {
+            let _permit = permit.acquire_owned().await.expect("semaphore closed");
+            let label_c = label.clone();
+            task::spawn_blocking(move || {
+                let mut attempts = 0;
+                loop {
+                    attempts += 1;
+                    println!("Executing: {}", cmd);
+                    let status = Command::new("sh")
+                        .arg("-c")
+                        .arg(&cmd)
+                        .status();
+                    if matches!(&status, Ok(s) if s.success()) || attempts > retries {
+                        return (status, label, output_files, attempts);
+                    }
+                    eprintln!("Encode of {} failed (attempt {}/{}), retrying", label, attempts, retries + 1);
+                }
+            })
+            .await
+            .unwrap_or_else(|e| (Err(std::io::Error::other(e.to_string())), label_c, Vec::new(), 1))
         }));
     }
 
     // Wait for all quality transcodes to complete in parallel
+    let mut failed_jobs: Vec<serde_json::Value> = Vec::new();
+    // Files whose ffmpeg run exited successfully; only these may be joined
+    let mut encoded_files: std::collections::HashSet<String> = std::collections::HashSet::new();
     for handle in transcode_handles {
-        match handle.await {
-            Ok((status, label, output_file)) => match status {
-                Ok(s) if s.success() => {
-                    println!("Generated: {}", output_file);
-                }
-                Ok(s) => {
-                    eprintln!("FFmpeg failed with exit code: {:?} for {}", s.code(), label);
-                }
-                Err(e) => {
-                    eprintln!("Failed to execute ffmpeg for {}: {}", label, e);
-                }
-            },
+        let (status, label, output_files, attempts) = match handle.await {
+            Ok(result) => result,
             Err(e) => {
                 eprintln!("Transcode task panicked: {}", e);
+                continue;
+            }
+        };
+        let error = match status {
+            Ok(s) if s.success() => {
+                println!("Generated: {}", output_files.join(", "));
+                encoded_files.extend(output_files);
+                continue;
+            }
+            Ok(s) => format!("exit code {:?}", s.code()),
+            Err(e) => e.to_string(),
+        };
+        eprintln!("FFmpeg failed for {} after {} attempt(s): {}", label, attempts, error);
+        // Don't let a truncated file pass as an encoded rung
+        for file in &output_files {
+            let _ = fs::remove_file(file);
+        }
+        failed_jobs.push(json!({ "job": label, "attempts": attempts, "error": error }));
+    }
+
+    // Join chunked renditions; a rendition with a failed or missing chunk is dropped
+    for (label, output_file, parts) in chunked_outputs {
+        if parts.iter().all(|(part, _)| encoded_files.contains(part) && fs::metadata(part).is_ok()) {
+            let list_path = format!("{}/output_{}_chunks.txt", output_dir, label);
+            let output_file_c = output_file.clone();
+            let parts_c = parts.clone();
+            let result = task::spawn_blocking(move || concat_encoded_chunks(&parts_c, &list_path, &output_file_c))
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()));
+            match result {
+                Ok(()) => println!("Generated: {} ({} chunks)", output_file, parts.len()),
+                Err(e) => {
+                    eprintln!("Failed to join chunks for {}: {}", label, e);
+                    let _ = fs::remove_file(&output_file);
+                }
             }
+        } else {
+            eprintln!("Failed or missing encoded chunks for {}, dropping rendition", label);
+        }
+        for (part, _) in &parts {
+            let _ = fs::remove_file(part);
         }
     }
 
     println!("Creating CMAF DASH manifest...");
     fmp4_files.retain(|file| fs::metadata(file).is_ok());
 
+    // Enforce the minimum-rendition policy and record what was encoded
+    let encoded_labels: Vec<&String> = outputs
+        .iter()
+        .map(|(_, _, label)| label)
+        .filter(|label| fmp4_files.contains(&format!("{}/output_{}.mp4", output_dir, label)))
+        .collect();
+    let missing_labels: Vec<&String> = outputs
+        .iter()
+        .map(|(_, _, label)| label)
+        .filter(|label| !encoded_labels.contains(label))
+        .collect();
+    let policy = &config.rendition_policy;
+    let requirement = if phase == EncodePhase::Preview { RenditionRequirement::Any } else { policy.require };
+    let policy_met = match requirement {
+        RenditionRequirement::Any => !encoded_labels.is_empty(),
+        RenditionRequirement::All => missing_labels.is_empty(),
+        RenditionRequirement::Top => outputs.first().is_some_and(|(_, _, top)| encoded_labels.contains(&top)),
+        RenditionRequirement::Count => encoded_labels.len() >= policy.min_renditions.max(1) as usize,
+    };
+    write_metadata_entry(output_dir, "renditions", json!({
+        "policy": format!("{:?}", requirement).to_lowercase(),
+        "encoded": encoded_labels,
+        "missing": missing_labels,
+        "failed_jobs": failed_jobs,
+        "policy_met": policy_met,
+    }));
+    if !policy_met {
+        eprintln!(
+            "Rendition policy '{:?}' not met: encoded {:?}, missing {:?}",
+            requirement, encoded_labels, missing_labels
+        );
+        return Err(ffmpeg_next::Error::External);
+    }
+
     if fmp4_files.is_empty() {
         eprintln!("No fMP4 files were successfully encoded, cannot create CMAF manifest");
         return Err(ffmpeg_next::Error::External);
@@ -4615,43 +8419,50 @@ async fn transcode_video(
     println!(
         "Found {} audio stream(s): {:?}",
         audio_streams.len(),
-        audio_streams.iter().map(|(_, lang, title, _)| {
-            format!("{}({})", if lang.is_empty() { "und" } else { lang }, if title.is_empty() { "none" } else { title })
+        audio_streams.iter().map(|(_, lang, title, _, channels, _)| {
+            format!("{}({}, {}ch)", if lang.is_empty() { "und" } else { lang }, if title.is_empty() { "none" } else { title }, channels)
         }).collect::<Vec<_>>()
     );
 
-    let audio_fmp4_files = if !audio_streams.is_empty() {
-        transcode_audio_streams_for_dash(input_file, output_dir, dash_audio_bitrate, &audio_streams, &config.dash).await
+    let (audio_fmp4_files, aac_fmp4_files) = if !audio_streams.is_empty() {
+        transcode_audio_streams_for_dash(input_file, output_dir, &audio_ladder, &audio_streams, &config.dash, loudness_config).await
     } else {
-        Vec::new()
+        (Vec::new(), Vec::new())
     };
 
     // Verify all audio streams were successfully transcoded
-    if audio_fmp4_files.len() != audio_streams.len() {
+    let expected_audio_renditions = audio_streams.len()
+        + audio_streams.iter().filter(|(_, _, _, _, channels, _)| surround_channels(*channels, &config.dash).is_some()).count();
+    if audio_fmp4_files.len() != expected_audio_renditions {
         eprintln!(
-            "WARNING: Audio stream count mismatch! Source has {} audio stream(s) but only {} were successfully transcoded. Missing tracks will not appear in CMAF manifest.",
+            "WARNING: Audio stream count mismatch! Source has {} audio stream(s) needing {} track(s) but only {} were successfully transcoded. Missing tracks will not appear in CMAF manifest.",
             audio_streams.len(),
+            expected_audio_renditions,
             audio_fmp4_files.len()
         );
     }
 
-    // Build CMAF inputs: video files first, then audio files
+    // Build CMAF inputs: video files first, then audio files, then the HLS-only AAC files
     let num_video_files = fmp4_files.len();
     let mut all_inputs: Vec<String> = fmp4_files
         .iter()
         .map(|file| format!("-i '{}'", file))
         .collect();
-    for (audio_file, _, _) in &audio_fmp4_files {
-        all_inputs.push(format!("-i '{}'", audio_file));
+    let packaged_audio: Vec<_> = audio_fmp4_files.iter().chain(&aac_fmp4_files).collect();
+    for (audio_files, ..) in &packaged_audio {
+        for (audio_file, _) in audio_files {
+            all_inputs.push(format!("-i '{}'", audio_file));
+        }
     }
     let dash_input_cmds = all_inputs.join(" ");
+    let num_audio_files: usize = packaged_audio.iter().map(|(files, ..)| files.len()).sum();
 
     // Build maps: video from video files, audio from audio-only files
     let mut maps = String::new();
     for track_num in 0..num_video_files {
         maps.push_str(&format!(" -map {}:v", track_num));
     }
-    for (audio_idx, _) in audio_fmp4_files.iter().enumerate() {
+    for audio_idx in 0..num_audio_files {
         maps.push_str(&format!(" -map {}:a:0", num_video_files + audio_idx));
     }
 
@@ -4659,35 +8470,43 @@ async fn transcode_video(
     // transcoded separately via transcode_audio_streams_for_dash(). If no audio
     // streams exist in the source, the CMAF manifest will simply have no audio.
 
-    // Build adaptation sets: one for video, one per audio language
+    // Build adaptation sets: one for video, one per audio track holding its bitrate ladder
     // Output stream indices: 0..num_video-1 are video, num_video..num_video+num_audio-1 are audio
     let num_video_outputs = num_video_files;
     let mut adaptation_sets = String::from("id=0,streams=v");
+    let mut metadata_args = String::new();
+    let mut output_audio_idx = 0;
 
-    if audio_fmp4_files.len() <= 1 {
-        if !audio_fmp4_files.is_empty() {
-            adaptation_sets.push_str(&format!(" id=1,streams={}", num_video_outputs));
-        }
-    } else {
-        for (audio_idx, _) in audio_fmp4_files.iter().enumerate() {
-            let output_stream_idx = num_video_outputs + audio_idx;
-            adaptation_sets.push_str(&format!(" id={},streams={}", audio_idx + 1, output_stream_idx));
+    for (track_idx, (audio_files, language, ..)) in packaged_audio.iter().enumerate() {
+        let streams: Vec<String> = (output_audio_idx..output_audio_idx + audio_files.len())
+            .map(|idx| (num_video_outputs + idx).to_string())
+            .collect();
+        adaptation_sets.push_str(&format!(" id={},streams={}", track_idx + 1, streams.join(",")));
+
+        for audio_idx in output_audio_idx..output_audio_idx + audio_files.len() {
+            if let Some(code) = container_language_code(language) {
+                let name = language_display_name(language).unwrap_or_else(|| code.clone());
+                metadata_args.push_str(&format!(" -metadata:s:a:{} language={}", audio_idx, code));
+                metadata_args.push_str(&format!(" -metadata:s:a:{} title=\"{}\"", audio_idx, name));
+                metadata_args.push_str(&format!(" -metadata:s:a:{} handler_name=\"{}\"", audio_idx, name));
+            }
         }
+        output_audio_idx += audio_files.len();
     }
 
-    let mut metadata_args = String::new();
-    for (audio_idx, (_, language, _)) in audio_fmp4_files.iter().enumerate() {
-        if !language.is_empty() {
-            metadata_args.push_str(&format!(" -metadata:s:a:{} language={}", audio_idx, language));
-            metadata_args.push_str(&format!(" -metadata:s:a:{} title=\"{}\"", audio_idx, language));
-            metadata_args.push_str(&format!(" -metadata:s:a:{} handler_name=\"{}\"", audio_idx, language));
-        }
-    }
+    // cenc is applied by ffmpeg's mp4 muxer while packaging; cbcs segments are encrypted afterwards
+    let encryption_args = match encryption {
+        Some(enc) if enc.key.scheme == EncryptionScheme::Cenc => format!(
+            "-format_options 'encryption_scheme=cenc-aes-ctr:encryption_key={}:encryption_kid={}'",
+            enc.key.key, enc.key.kid
+        ),
+        _ => String::new(),
+    };
 
     let dash_output_cmd = format!(
         "ffmpeg -nostdin -y -analyzeduration 1000M -probesize 1000M {} {} \
          -c copy -map_metadata -1 {} \
-         -f dash -dash_segment_type mp4 \
+         -f dash -dash_segment_type mp4 {} \
          -use_template 1 -use_timeline 1 \
          -seg_duration {} \
          -window_size 0 -extra_window_size 0 \
@@ -4701,7 +8520,8 @@ async fn transcode_video(
         dash_input_cmds,
         maps,
         metadata_args,
-        config.dash.segment_duration,
+        encryption_args,
+        segment_duration_seconds(&config.dash),
         adaptation_sets,
         dash_output_dir
     );
@@ -4722,15 +8542,96 @@ async fn transcode_video(
 
     // Post-process MPD to add <Label> and <Role> elements for audio track selection
     let mpd_path = format!("{}/video.mpd", dash_output_dir);
-    post_process_dash_manifest(&mpd_path, &audio_fmp4_files);
+    let default_audio = choose_default_audio(&audio_fmp4_files, &config.dash.preferred_audio_languages);
+    // Adaptation set ids follow the track order above; the AAC tracks come last
+    let aac_set_ids: Vec<u32> = (audio_fmp4_files.len()..packaged_audio.len()).map(|idx| idx as u32 + 1).collect();
+    post_process_dash_manifest(&mpd_path, &audio_fmp4_files, default_audio, &aac_set_ids);
 
     // Post-process HLS master playlist to replace generic audio names with language labels
     let m3u8_path = format!("{}/video.m3u8", dash_output_dir);
-    post_process_hls_manifest(&m3u8_path, &audio_fmp4_files);
+    // Audio bitrate each video variant is paired with, in variant order
+    let variant_audio_bitrates: Vec<u32> = fmp4_files
+        .iter()
+        .map(|file| {
+            outputs
+                .iter()
+                .find(|(_, _, label)| *file == format!("{}/output_{}.mp4", output_dir, label))
+                .map(|(_, _, label)| rung_audio_bitrate(label))
+                .unwrap_or(audio_ladder[0])
+        })
+        .collect();
+    post_process_hls_manifest(&m3u8_path, &audio_fmp4_files, &aac_fmp4_files, default_audio, &audio_ladder, &variant_audio_bitrates);
+
+    if let Some(enc) = encryption {
+        let encrypted = if enc.key.scheme == EncryptionScheme::Cbcs {
+            encrypt_segments_cbcs(&dash_output_dir, enc)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = encrypted.and_then(|_| signal_encryption(&dash_output_dir, enc)) {
+            eprintln!("Encryption failed: {}", e);
+            return Err(ffmpeg_next::Error::External);
+        }
+        write_metadata_entry(output_dir, "encryption", json!({
+            "scheme": enc.key.scheme,
+            "kid": kid_uuid(&enc.key.kid),
+            "license_url": enc.license_url,
+            "key_url": enc.key_url,
+        }));
+    }
+
+    // The progressive download is built next to the thumbnails and owns the intermediates it
+    // reads: the top rendition in remux mode and the best rendition of every audio track
+    let mut download_inputs: Vec<String> = Vec::new();
+    let mut download_handle: Option<task::JoinHandle<Option<u64>>> = None;
+    if phase == EncodePhase::Full && config.download.enabled {
+        if encryption.is_some() {
+            eprintln!("Warning: Skipping the download, a clear copy would bypass the encryption");
+        } else {
+            let top_file = outputs
+                .first()
+                .map(|(_, _, label)| format!("{}/output_{}.mp4", output_dir, label))
+                .filter(|file| fmp4_files.contains(file))
+                .or_else(|| fmp4_files.first().cloned());
+            let (video_input, video_filter) = match config.download.mode {
+                DownloadMode::H264 => {
+                    // Same crop, tonemapping and logo as the renditions, in software
+                    let height = original_height.min(config.download.max_height.max(2)) / 2 * 2;
+                    let tonemap = if tonemap_filter.is_empty() { String::new() } else { format!("{},", tonemap_filter) };
+                    let filter = format!("{}{}{}scale=-2:{},format=yuv420p", crop_prefix, tonemap, watermark_sw, height);
+                    (Some(input_file.to_string()), Some(filter))
+                }
+                DownloadMode::Remux => (top_file.clone(), None),
+            };
+            let audio: Vec<(String, String, String)> = audio_fmp4_files
+                .iter()
+                .filter_map(|(files, language, title, ..)| {
+                    files.iter().max_by_key(|(_, bitrate)| *bitrate).map(|(file, _)| (file.clone(), language.clone(), title.clone()))
+                })
+                .collect();
+            if let Some(video_input) = video_input {
+                if config.download.mode == DownloadMode::Remux {
+                    download_inputs.push(video_input.clone());
+                }
+                download_inputs.extend(audio.iter().map(|(file, ..)| file.clone()));
+                let output_dir_d = output_dir.to_string();
+                let download_config = config.download.clone();
+                let fps_cap = config.fps_cap;
+                let inputs = download_inputs.clone();
+                download_handle = Some(task::spawn_blocking(move || {
+                    let size = build_download(&output_dir_d, &video_input, video_filter.as_deref(), &audio, default_audio, &download_config, fps_cap);
+                    for file in inputs {
+                        let _ = fs::remove_file(&file);
+                    }
+                    size
+                }));
+            }
+        }
+    }
 
     // Clean up intermediate fMP4 files
     println!("Remove fMP4 files...");
-    for file in fmp4_files {
+    for file in fmp4_files.into_iter().filter(|file| !download_inputs.contains(file)) {
         if let Err(e) = fs::remove_file(&file) {
             eprintln!("Warning: Failed to delete intermediate fMP4 file {}: {}", file, e);
         }
@@ -4738,19 +8639,52 @@ async fn transcode_video(
 
     // Clean up intermediate audio fMP4 files
     println!("Remove audio fMP4 files...");
-    for (audio_file, _, _) in &audio_fmp4_files {
+    for (audio_file, _) in audio_fmp4_files.iter().chain(&aac_fmp4_files).flat_map(|(files, ..)| files) {
+        if download_inputs.contains(audio_file) {
+            continue;
+        }
         if let Err(e) = fs::remove_file(audio_file) {
             eprintln!("Warning: Failed to delete intermediate audio fMP4 file {}: {}", audio_file, e);
         }
     }
 
-    // Generate thumbnails and preview sprites in parallel
-    let random_time = if duration > 0.1 {
-        rand::rng().random_range(0.0..duration)
+    if phase == EncodePhase::Preview {
+        check_package(output_dir, &dash_output_dir, duration, expected_audio_renditions, &config.dash)?;
+        if let Some(slot) = slot {
+            swap_video_slot(output_dir, slot, published_slot.as_deref())?;
+        }
+
+        // Plain mid-point thumbnail; the full phase picks the real poster later
+        let thumbnail_time = if duration > 0.1 { duration / 2.0 } else { 0.0 };
+        let thumbnail_cmd = format!(
+            "ffmpeg -nostdin -y -ss {:.2} -i '{}' -vf '{}scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2:black' -frames:v 1 -update 1 '{}/thumbnail.jpg'",
+            thumbnail_time, input_file, thumb_prefix, config.thumbnail.width, config.thumbnail.height, config.thumbnail.width, config.thumbnail.height, output_dir
+        );
+        let _ = task::spawn_blocking(move || {
+            println!("Executing: {}", thumbnail_cmd);
+            let _ = Command::new("sh").arg("-c").arg(&thumbnail_cmd).status();
+        }).await;
+        println!("Preview phase complete");
+        return Ok(());
+    }
+
+    // Pick the poster frame, then generate thumbnails and preview sprites in parallel
+    let poster_candidates = if config.thumbnail.smart_selection && duration > 0.1 {
+        let input_file_p = input_file.to_string();
+        let crop_prefix_p = crop_prefix.clone();
+        let thumb_config_p = config.thumbnail.clone();
+        task::spawn_blocking(move || select_poster_frames(&input_file_p, duration, &crop_prefix_p, &thumb_config_p))
+            .await
+            .unwrap_or_default()
     } else {
-        0.0
+        Vec::new()
+    };
+    let thumbnail_time = match poster_candidates.first() {
+        Some(best) => best.time,
+        None if duration > 0.1 => duration / 2.0,
+        None => 0.0,
     };
-    println!("thumbnail selected time: {:.2} seconds", random_time);
+    println!("thumbnail selected time: {:.2} seconds", thumbnail_time);
 
     // Create preview output directory before spawning sprite tasks
     let preview_output_dir = format!("{}/previews", output_dir);
@@ -4783,8 +8717,8 @@ async fn transcode_video(
 
     // JPG thumbnail
     let thumbnail_jpg_cmd = format!(
-        "ffmpeg -nostdin -y -ss {:.2} -i '{}' -vf 'scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2:black' -frames:v 1 -update 1 '{}/thumbnail.jpg'",
-        random_time, input_file, config.thumbnail.width, config.thumbnail.height, config.thumbnail.width, config.thumbnail.height, output_dir
+        "ffmpeg -nostdin -y -ss {:.2} -i '{}' -vf '{}scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2:black' -frames:v 1 -update 1 '{}/thumbnail.jpg'",
+        thumbnail_time, input_file, thumb_prefix, config.thumbnail.width, config.thumbnail.height, config.thumbnail.width, config.thumbnail.height, output_dir
     );
     post_handles.push(task::spawn_blocking(move || {
         println!("Executing: {}", thumbnail_jpg_cmd);
@@ -4793,8 +8727,8 @@ async fn transcode_video(
 
     // AVIF thumbnail
     let thumbnail_avif_cmd = format!(
-        "ffmpeg -nostdin -y -ss {:.2} -i '{}' -vf 'scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2:black' -frames:v 1 -c:v libsvtav1 -svtav1-params avif=1 -pix_fmt yuv420p10le -update 1 '{}/thumbnail.avif'",
-        random_time, input_file, config.thumbnail.width, config.thumbnail.height, config.thumbnail.width, config.thumbnail.height, output_dir
+        "ffmpeg -nostdin -y -ss {:.2} -i '{}' -vf '{}scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2:black' -frames:v 1 -c:v libsvtav1 -svtav1-params avif=1 -pix_fmt yuv420p10le -update 1 '{}/thumbnail.avif'",
+        thumbnail_time, input_file, thumb_prefix, config.thumbnail.width, config.thumbnail.height, config.thumbnail.width, config.thumbnail.height, output_dir
     );
     post_handles.push(task::spawn_blocking(move || {
         println!("Executing: {}", thumbnail_avif_cmd);
@@ -4803,14 +8737,57 @@ async fn transcode_video(
 
     // Small AVIF thumbnail (352x198) for bandwidth-efficient small previews
     let thumbnail_sm_avif_cmd = format!(
-        "ffmpeg -nostdin -y -ss {:.2} -i '{}' -vf 'scale=352:198:force_original_aspect_ratio=decrease,pad=352:198:(ow-iw)/2:(oh-ih)/2:black' -frames:v 1 -c:v libsvtav1 -svtav1-params avif=1 -pix_fmt yuv420p10le -update 1 '{}/thumbnail-sm.avif'",
-        random_time, input_file, output_dir
+        "ffmpeg -nostdin -y -ss {:.2} -i '{}' -vf '{}scale=352:198:force_original_aspect_ratio=decrease,pad=352:198:(ow-iw)/2:(oh-ih)/2:black' -frames:v 1 -c:v libsvtav1 -svtav1-params avif=1 -pix_fmt yuv420p10le -update 1 '{}/thumbnail-sm.avif'",
+        thumbnail_time, input_file, thumb_prefix, output_dir
     );
     post_handles.push(task::spawn_blocking(move || {
         println!("Executing: {}", thumbnail_sm_avif_cmd);
         let _ = Command::new("sh").arg("-c").arg(&thumbnail_sm_avif_cmd).status();
     }));
 
+    // Ranked poster candidates for the uploader to choose from
+    if !poster_candidates.is_empty() {
+        let candidates_dir = format!("{}/thumbnails", output_dir);
+        if let Err(e) = fs::create_dir_all(&candidates_dir) {
+            eprintln!("Warning: Failed to create thumbnail candidates directory: {}", e);
+        }
+        let mut candidate_entries = Vec::new();
+        for (rank, candidate) in poster_candidates.iter().take(config.thumbnail.candidates as usize).enumerate() {
+            let candidate_cmd = format!(
+                "ffmpeg -nostdin -y -ss {:.2} -i '{}' -vf '{}scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2:black' -frames:v 1 -update 1 '{}/candidate_{}.jpg'",
+                candidate.time, input_file, thumb_prefix, config.thumbnail.width, config.thumbnail.height, config.thumbnail.width, config.thumbnail.height, candidates_dir, rank
+            );
+            post_handles.push(task::spawn_blocking(move || {
+                println!("Executing: {}", candidate_cmd);
+                let _ = Command::new("sh").arg("-c").arg(&candidate_cmd).status();
+            }));
+            candidate_entries.push(json!({
+                "rank": rank,
+                "file": format!("thumbnails/candidate_{}.jpg", rank),
+                "time": (candidate.time * 100.0).round() / 100.0,
+                "score": (candidate.score * 1000.0).round() / 1000.0,
+                "brightness": candidate.brightness,
+                "contrast": candidate.contrast,
+                "blur": candidate.blur,
+            }));
+        }
+        write_metadata_entry(output_dir, "poster_candidates", serde_json::Value::Array(candidate_entries));
+    }
+
+    // Animated hover preview (MP4, WebP, AVIF)
+    if config.hover_preview.enabled && duration > 0.0 {
+        let input_file_h = input_file.to_string();
+        let preview_dir_h = preview_output_dir.clone();
+        let sprite_prefix_h = sprite_prefix.clone();
+        let hover_config = config.hover_preview.clone();
+        post_handles.push(task::spawn_blocking(move || {
+            println!("Generating hover preview ({} x {:.1}s)...", hover_config.segments, hover_config.segment_duration);
+            if let Err(e) = generate_hover_preview(&input_file_h, &preview_dir_h, duration, &sprite_prefix_h, &hover_config) {
+                eprintln!("Warning: {}", e);
+            }
+        }));
+    }
+
     // Sprite files with throttled parallelism
     let parallel_limit = config.preview_sprites.parallel_limit.max(1) as usize;
     let semaphore = Arc::new(Semaphore::new(parallel_limit));
@@ -4830,8 +8807,8 @@ async fn transcode_video(
         let duration_for_this_file = thumbs_in_this_file as f64 * interval_seconds;
 
         let tile_filter = format!(
-            "fps=1/{:.3},scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2,tile={}x{}",
-            interval_seconds, thumb_width, thumb_height, thumb_width, thumb_height,
+            "fps=1/{:.3},{}scale={}:{}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2,tile={}x{}",
+            interval_seconds, sprite_prefix, thumb_width, thumb_height, thumb_width, thumb_height,
             sprites_across, rows_in_this_file
         );
 
@@ -4871,6 +8848,21 @@ async fn transcode_video(
         let _ = handle.await;
     }
 
+    // Subtitles are embedded once the captions are published
+    if let Some(handle) = download_handle {
+        if let Ok(Some(size)) = handle.await {
+            let mut entry = json!({
+                "file": "download.mp4",
+                "size": size,
+                "mode": config.download.mode,
+            });
+            if config.download.subtitles {
+                entry["subtitles"] = json!([]);
+            }
+            write_metadata_entry(output_dir, "download", entry);
+        }
+    }
+
     // Generate WebVTT file with sprite coordinates
     let mut vtt_cues: Vec<ThumbnailCue> = Vec::new();
 
@@ -4918,5 +8910,20 @@ async fn transcode_video(
         num_sprite_files
     );
 
+    // Reference the sprites from the manifests so standard players show scrub previews
+    if (0..num_sprite_files).all(|i| std::path::Path::new(&format!("{}/preview_sprite_{}.avif", preview_output_dir, i)).exists()) {
+        add_trick_play_tracks(&dash_output_dir, &preview_output_dir, num_sprite_files, num_thumbnails, &config.preview_sprites);
+    } else {
+        eprintln!("Warning: Some preview sprites are missing, skipping trick-play tracks");
+    }
+
+    check_package(output_dir, &dash_output_dir, duration, expected_audio_renditions, &config.dash)?;
+
+    // Swap the finished ladder in for the published preview
+    if let Some(slot) = slot {
+        swap_video_slot(output_dir, slot, published_slot.as_deref())?;
+        println!("Replaced preview with the full ladder");
+    }
+
     Ok(())
 }
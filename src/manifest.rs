@@ -0,0 +1,820 @@
+//! Minimal manifest models for the DASH MPD and HLS playlists ffmpeg writes.
+//!
+//! Manifests are parsed into a tree (MPD) or a list of tags (M3U8), edited through
+//! typed helpers and serialized again, so labels, roles and languages coming from
+//! user metadata are always escaped instead of being spliced into raw text.
+
+use std::fs;
+
+/// A node of an XML document: element or (unescaped) text.
+#[derive(Debug, Clone)]
+pub enum XmlNode {
+    Element(XmlElement),
+    Text(String),
+}
+
+/// An XML element with ordered attributes and children. Attribute values and text are
+/// stored unescaped; escaping happens only when serializing.
+#[derive(Debug, Clone)]
+pub struct XmlElement {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<XmlNode>,
+}
+
+impl XmlElement {
+    pub fn new(name: &str) -> Self {
+        XmlElement { name: name.to_string(), attrs: Vec::new(), children: Vec::new() }
+    }
+
+    /// Builder-style attribute setter.
+    pub fn with_attr(mut self, key: &str, value: &str) -> Self {
+        self.set_attr(key, value);
+        self
+    }
+
+    /// Builder-style child append.
+    pub fn with_child(mut self, child: XmlElement) -> Self {
+        self.children.push(XmlNode::Element(child));
+        self
+    }
+
+    /// Builder-style text content.
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.children.push(XmlNode::Text(text.to_string()));
+        self
+    }
+
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn set_attr(&mut self, key: &str, value: &str) {
+        match self.attrs.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.attrs.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &XmlElement> {
+        self.children.iter().filter_map(|c| match c {
+            XmlNode::Element(e) => Some(e),
+            XmlNode::Text(_) => None,
+        })
+    }
+
+    pub fn elements_mut(&mut self) -> impl Iterator<Item = &mut XmlElement> {
+        self.children.iter_mut().filter_map(|c| match c {
+            XmlNode::Element(e) => Some(e),
+            XmlNode::Text(_) => None,
+        })
+    }
+
+    /// First direct child with the given name.
+    pub fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.elements().find(|e| e.name == name)
+    }
+
+    /// Remove direct children with the given name.
+    pub fn remove_children(&mut self, name: &str) {
+        self.children.retain(|c| !matches!(c, XmlNode::Element(e) if e.name == name));
+    }
+
+    /// Insert a child element before any other element children, so descriptors such as
+    /// `Label` and `Role` land ahead of `Representation` as the MPD schema orders them.
+    pub fn prepend_child(&mut self, child: XmlElement) {
+        let pos = self.children.iter().position(|c| matches!(c, XmlNode::Element(_))).unwrap_or(self.children.len());
+        self.children.insert(pos, XmlNode::Element(child));
+    }
+
+    pub fn push_child(&mut self, child: XmlElement) {
+        self.children.push(XmlNode::Element(child));
+    }
+
+    /// Concatenated text content of this element.
+    pub fn text(&self) -> String {
+        self.children
+            .iter()
+            .filter_map(|c| match c {
+                XmlNode::Text(t) => Some(t.as_str()),
+                XmlNode::Element(_) => None,
+            })
+            .collect()
+    }
+}
+
+pub fn escape_xml(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Control characters other than tab/newline are not allowed in XML 1.0
+            c if (c as u32) < 0x20 && c != '\t' && c != '\n' && c != '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape_xml(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';') else { break };
+        let entity = &rest[1..semi];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn parse_attrs(mut rest: &str) -> Result<Vec<(String, String)>, String> {
+    let mut attrs = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(attrs);
+        }
+        let eq = rest.find('=').ok_or_else(|| format!("Malformed attribute near '{}'", rest))?;
+        let key = rest[..eq].trim().to_string();
+        rest = rest[eq + 1..].trim_start();
+        let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')
+            .ok_or_else(|| format!("Unquoted value for attribute '{}'", key))?;
+        let end = rest[1..].find(quote).ok_or_else(|| format!("Unterminated value for attribute '{}'", key))?;
+        attrs.push((key, unescape_xml(&rest[1..1 + end])));
+        rest = &rest[end + 2..];
+    }
+}
+
+/// Parse an XML document into its root element. Declarations, comments and
+/// processing instructions are dropped; whitespace-only text is ignored. CDATA
+/// sections become plain text and are escaped when written back.
+pub fn parse_xml(content: &str) -> Result<XmlElement, String> {
+    let mut stack: Vec<XmlElement> = Vec::new();
+    let mut root: Option<XmlElement> = None;
+    let mut rest = content;
+
+    while !rest.is_empty() {
+        let Some(lt) = rest.find('<') else {
+            if !rest.trim().is_empty() {
+                return Err("Text outside of the root element".to_string());
+            }
+            break;
+        };
+        let text = &rest[..lt];
+        if !text.trim().is_empty() {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(XmlNode::Text(unescape_xml(text.trim()))),
+                None => return Err("Text outside of the root element".to_string()),
+            }
+        }
+        rest = &rest[lt..];
+
+        if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").ok_or("Unterminated CDATA section")?;
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(XmlNode::Text(cdata[..end].to_string())),
+                None => return Err("CDATA outside of the root element".to_string()),
+            }
+            rest = &cdata[end + 3..];
+            continue;
+        }
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").ok_or("Unterminated comment")?;
+            rest = &rest[end + 3..];
+            continue;
+        }
+        if rest.starts_with("<?") || rest.starts_with("<!") {
+            let end = rest.find('>').ok_or("Unterminated declaration")?;
+            rest = &rest[end + 1..];
+            continue;
+        }
+
+        let gt = rest.find('>').ok_or("Unterminated tag")?;
+        let inner = &rest[1..gt];
+        rest = &rest[gt + 1..];
+
+        if let Some(name) = inner.strip_prefix('/') {
+            let element = stack.pop().ok_or_else(|| format!("Unexpected closing tag </{}>", name.trim()))?;
+            if element.name != name.trim() {
+                return Err(format!("Mismatched closing tag </{}> for <{}>", name.trim(), element.name));
+            }
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(XmlNode::Element(element)),
+                None => root = Some(element),
+            }
+            continue;
+        }
+
+        let self_closing = inner.ends_with('/');
+        let inner = inner.trim_end_matches('/');
+        let name_end = inner.find(char::is_whitespace).unwrap_or(inner.len());
+        let element = XmlElement {
+            name: inner[..name_end].to_string(),
+            attrs: parse_attrs(&inner[name_end..])?,
+            children: Vec::new(),
+        };
+        if self_closing {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(XmlNode::Element(element)),
+                None => root = Some(element),
+            }
+        } else {
+            stack.push(element);
+        }
+    }
+
+    if let Some(open) = stack.last() {
+        return Err(format!("Unclosed element <{}>", open.name));
+    }
+    root.ok_or_else(|| "Empty document".to_string())
+}
+
+fn write_element(out: &mut String, element: &XmlElement, depth: usize) {
+    let indent = "\t".repeat(depth);
+    out.push_str(&indent);
+    out.push('<');
+    out.push_str(&element.name);
+    for (key, value) in &element.attrs {
+        out.push_str(&format!(" {}=\"{}\"", key, escape_xml(value)));
+    }
+    if element.children.is_empty() {
+        out.push_str("/>\n");
+        return;
+    }
+    if element.children.iter().all(|c| matches!(c, XmlNode::Text(_))) {
+        out.push_str(&format!(">{}</{}>\n", escape_xml(&element.text()), element.name));
+        return;
+    }
+    out.push_str(">\n");
+    for child in &element.children {
+        match child {
+            XmlNode::Element(e) => write_element(out, e, depth + 1),
+            XmlNode::Text(t) => out.push_str(&format!("{}\t{}\n", indent, escape_xml(t))),
+        }
+    }
+    out.push_str(&format!("{}</{}>\n", indent, element.name));
+}
+
+/// Serialize a document with an XML declaration and tab indentation (as ffmpeg does).
+pub fn write_xml(root: &XmlElement) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    write_element(&mut out, root, 0);
+    out
+}
+
+/// DASH manifest: the parsed MPD root with helpers for the AdaptationSets of every Period.
+pub struct Mpd {
+    pub root: XmlElement,
+}
+
+impl Mpd {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        let root = parse_xml(&content).map_err(|e| format!("Cannot parse {}: {}", path, e))?;
+        if root.name != "MPD" {
+            return Err(format!("{} is not an MPD (root <{}>)", path, root.name));
+        }
+        Ok(Mpd { root })
+    }
+
+    /// Write to a temporary file and rename, so readers never see a half-written manifest.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        write_atomically(path, &write_xml(&self.root))
+    }
+
+    /// `mediaPresentationDuration` in seconds.
+    pub fn presentation_duration(&self) -> Option<f64> {
+        parse_iso8601_duration(self.root.attr("mediaPresentationDuration")?)
+    }
+
+    pub fn periods_mut(&mut self) -> impl Iterator<Item = &mut XmlElement> {
+        self.root.elements_mut().filter(|e| e.name == "Period")
+    }
+
+    pub fn adaptation_sets(&self) -> impl Iterator<Item = &XmlElement> {
+        self.root
+            .elements()
+            .filter(|e| e.name == "Period")
+            .flat_map(|p| p.elements().filter(|e| e.name == "AdaptationSet"))
+    }
+
+    pub fn adaptation_sets_mut(&mut self) -> impl Iterator<Item = &mut XmlElement> {
+        self.periods_mut().flat_map(|p| p.elements_mut().filter(|e| e.name == "AdaptationSet"))
+    }
+
+    /// AdaptationSets of one content type ("video", "audio", "text", "image"), in order.
+    pub fn adaptation_sets_of_mut(&mut self, content_type: &str) -> Vec<&mut XmlElement> {
+        self.adaptation_sets_mut()
+            .filter(|a| adaptation_content_type(a) == content_type)
+            .collect()
+    }
+
+    /// Remove every AdaptationSet of one content type, so regenerated tracks replace older ones.
+    pub fn remove_adaptation_sets(&mut self, content_type: &str) {
+        for period in self.periods_mut() {
+            period.children.retain(|c| {
+                !matches!(c, XmlNode::Element(e) if e.name == "AdaptationSet" && adaptation_content_type(e) == content_type)
+            });
+        }
+    }
+
+    /// Remove the AdaptationSet with the given id.
+    pub fn remove_adaptation_set(&mut self, id: &str) {
+        for period in self.periods_mut() {
+            period.children.retain(|c| !matches!(c, XmlNode::Element(e) if e.name == "AdaptationSet" && e.attr("id") == Some(id)));
+        }
+    }
+
+    /// Append an AdaptationSet to the first Period.
+    pub fn push_adaptation_set(&mut self, adaptation_set: XmlElement) -> Result<(), String> {
+        let period = self.periods_mut().next().ok_or("MPD has no Period")?;
+        period.push_child(adaptation_set);
+        Ok(())
+    }
+
+    /// Next free AdaptationSet id.
+    pub fn next_adaptation_set_id(&self) -> u32 {
+        self.adaptation_sets()
+            .filter_map(|a| a.attr("id").and_then(|id| id.parse::<u32>().ok()))
+            .max()
+            .map(|id| id + 1)
+            .unwrap_or(0)
+    }
+}
+
+/// Parse an ISO 8601 duration as used by MPDs (`PT1H2M3.5S`, `P1DT2H`) into seconds.
+pub fn parse_iso8601_duration(value: &str) -> Option<f64> {
+    let rest = value.trim().strip_prefix('P')?;
+    let (date, time) = rest.split_once('T').unwrap_or((rest, ""));
+    let mut seconds = 0.0;
+    for (part, units) in [(date, &[('D', 86400.0)][..]), (time, &[('H', 3600.0), ('M', 60.0), ('S', 1.0)][..])] {
+        let mut number = String::new();
+        for ch in part.chars() {
+            if ch.is_ascii_digit() || ch == '.' {
+                number.push(ch);
+            } else {
+                let scale = units.iter().find(|(u, _)| *u == ch)?.1;
+                seconds += number.parse::<f64>().ok()? * scale;
+                number.clear();
+            }
+        }
+        if !number.is_empty() {
+            return None;
+        }
+    }
+    Some(seconds)
+}
+
+/// Content type of an AdaptationSet, falling back to the mimeType prefix
+/// (ffmpeg always writes contentType, other packagers may not).
+pub fn adaptation_content_type(adaptation_set: &XmlElement) -> String {
+    adaptation_set
+        .attr("contentType")
+        .or_else(|| adaptation_set.attr("mimeType").and_then(|m| m.split('/').next()))
+        .unwrap_or("")
+        .to_string()
+}
+
+/// Set the descriptors of an AdaptationSet that players use to tell tracks apart.
+/// Existing Label/Role/Accessibility elements are replaced.
+pub fn describe_adaptation_set(
+    adaptation_set: &mut XmlElement,
+    label: &str,
+    language: Option<&str>,
+    roles: &[&str],
+    accessibility: &[(&str, &str)],
+) {
+    if let Some(lang) = language {
+        adaptation_set.set_attr("lang", lang);
+    }
+    adaptation_set.remove_children("Label");
+    adaptation_set.remove_children("Role");
+    adaptation_set.remove_children("Accessibility");
+    // Prepended in reverse so the final order is the schema's: Label, Accessibility..., Role...
+    for role in roles.iter().rev() {
+        adaptation_set.prepend_child(
+            XmlElement::new("Role").with_attr("schemeIdUri", "urn:mpeg:dash:role:2011").with_attr("value", role),
+        );
+    }
+    for (scheme, value) in accessibility.iter().rev() {
+        adaptation_set.prepend_child(XmlElement::new("Accessibility").with_attr("schemeIdUri", scheme).with_attr("value", value));
+    }
+    adaptation_set.prepend_child(XmlElement::new("Label").with_text(label));
+}
+
+/// Signal CENC protection on an AdaptationSet: the common `mp4protection` descriptor with
+/// the default KID (UUID form), followed by the ClearKey system with an optional license URL.
+/// Both go before any Label/Role, as the schema orders ContentProtection first.
+pub fn protect_adaptation_set(adaptation_set: &mut XmlElement, scheme: &str, default_kid: &str, license_url: Option<&str>) {
+    let mut clearkey = XmlElement::new("ContentProtection")
+        .with_attr("schemeIdUri", "urn:uuid:e2719d58-a985-b3c9-781a-b030af78d30e")
+        .with_attr("value", "ClearKey1.0");
+    if let Some(url) = license_url {
+        clearkey = clearkey.with_child(XmlElement::new("clearkey:Laurl").with_attr("Lic_type", "EME-1.0").with_text(url));
+    }
+    adaptation_set.remove_children("ContentProtection");
+    adaptation_set.prepend_child(clearkey);
+    adaptation_set.prepend_child(
+        XmlElement::new("ContentProtection")
+            .with_attr("schemeIdUri", "urn:mpeg:dash:mp4protection:2011")
+            .with_attr("value", scheme)
+            .with_attr("cenc:default_KID", default_kid),
+    );
+}
+
+/// An attribute value in an HLS attribute list.
+#[derive(Debug, Clone)]
+pub enum HlsValue {
+    Quoted(String),
+    Plain(String),
+}
+
+impl HlsValue {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HlsValue::Quoted(v) | HlsValue::Plain(v) => v,
+        }
+    }
+}
+
+/// One line of an M3U8 playlist.
+#[derive(Debug, Clone)]
+pub enum HlsLine {
+    /// `#EXT-X-NAME:KEY=VALUE,...` with a parsed attribute list
+    Tag { name: String, attrs: Vec<(String, HlsValue)> },
+    /// Any other tag or comment, kept verbatim (e.g. `#EXTINF:4.000,`)
+    Raw(String),
+    /// A URI line
+    Uri(String),
+}
+
+impl HlsLine {
+    /// A new attribute-list tag without attributes.
+    pub fn tag(name: &str) -> Self {
+        HlsLine::Tag { name: name.to_string(), attrs: Vec::new() }
+    }
+
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        match self {
+            HlsLine::Tag { attrs, .. } => attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Set a quoted-string attribute. HLS has no escaping, so characters a quoted
+    /// string cannot hold (double quotes, line breaks) are replaced.
+    pub fn set_quoted(&mut self, key: &str, value: &str) {
+        let clean: String = value
+            .chars()
+            .map(|c| match c {
+                '"' => '\'',
+                '\r' | '\n' => ' ',
+                c => c,
+            })
+            .collect();
+        self.set_value(key, HlsValue::Quoted(clean));
+    }
+
+    /// Set an enumerated or numeric attribute (written without quotes).
+    pub fn set_plain(&mut self, key: &str, value: &str) {
+        self.set_value(key, HlsValue::Plain(value.to_string()));
+    }
+
+    pub fn remove_attr(&mut self, key: &str) {
+        if let HlsLine::Tag { attrs, .. } = self {
+            attrs.retain(|(k, _)| k != key);
+        }
+    }
+
+    fn set_value(&mut self, key: &str, value: HlsValue) {
+        if let HlsLine::Tag { attrs, .. } = self {
+            match attrs.iter_mut().find(|(k, _)| k == key) {
+                Some((_, v)) => *v = value,
+                None => attrs.push((key.to_string(), value)),
+            }
+        }
+    }
+
+    pub fn is_tag(&self, tag: &str) -> bool {
+        matches!(self, HlsLine::Tag { name, .. } if name == tag)
+    }
+}
+
+/// Tags whose payload is an attribute list and is parsed into `HlsLine::Tag`.
+const HLS_ATTRIBUTE_TAGS: &[&str] = &[
+    "EXT-X-MEDIA",
+    "EXT-X-STREAM-INF",
+    "EXT-X-I-FRAME-STREAM-INF",
+    "EXT-X-IMAGE-STREAM-INF",
+    "EXT-X-MAP",
+    "EXT-X-KEY",
+    "EXT-X-SESSION-KEY",
+    "EXT-X-SESSION-DATA",
+    "EXT-X-TILES",
+];
+
+fn parse_hls_attrs(list: &str) -> Vec<(String, HlsValue)> {
+    let mut attrs = Vec::new();
+    let mut rest = list;
+    while !rest.is_empty() {
+        let Some(eq) = rest.find('=') else { break };
+        let key = rest[..eq].trim().to_string();
+        rest = &rest[eq + 1..];
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let v = HlsValue::Quoted(quoted[..end].to_string());
+            rest = quoted.get(end + 1..).unwrap_or("");
+            v
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let v = HlsValue::Plain(rest[..end].to_string());
+            rest = &rest[end..];
+            v
+        };
+        attrs.push((key, value));
+        rest = rest.strip_prefix(',').unwrap_or(rest);
+    }
+    attrs
+}
+
+/// An M3U8 playlist (master or media) as a list of lines.
+pub struct Playlist {
+    pub lines: Vec<HlsLine>,
+}
+
+impl Playlist {
+    pub fn parse(content: &str) -> Self {
+        let lines = content
+            .lines()
+            .map(str::trim_end)
+            .filter(|l| !l.is_empty())
+            .map(|line| {
+                if let Some(tag) = line.strip_prefix('#') {
+                    if let Some((name, list)) = tag.split_once(':') {
+                        if HLS_ATTRIBUTE_TAGS.contains(&name) {
+                            return HlsLine::Tag { name: name.to_string(), attrs: parse_hls_attrs(list) };
+                        }
+                    }
+                    HlsLine::Raw(line.to_string())
+                } else {
+                    HlsLine::Uri(line.to_string())
+                }
+            })
+            .collect();
+        Playlist { lines }
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        fs::read_to_string(path)
+            .map(|c| Playlist::parse(&c))
+            .map_err(|e| format!("Cannot read {}: {}", path, e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        write_atomically(path, &self.to_string())
+    }
+
+    pub fn tags_mut<'a>(&'a mut self, tag: &'a str) -> impl Iterator<Item = &'a mut HlsLine> + 'a {
+        self.lines.iter_mut().filter(move |l| l.is_tag(tag))
+    }
+
+    /// Insert lines before the first variant stream, where rendition groups (`EXT-X-MEDIA`) belong.
+    pub fn insert_before_variants(&mut self, lines: Vec<HlsLine>) {
+        let pos = self.lines.iter().position(|l| l.is_tag("EXT-X-STREAM-INF")).unwrap_or(self.lines.len());
+        self.lines.splice(pos..pos, lines);
+    }
+
+    /// Insert lines before the first `tag`, or at the end when the playlist has none.
+    pub fn insert_before_tag(&mut self, tag: &str, lines: Vec<HlsLine>) {
+        let pos = self.lines.iter().position(|l| l.is_tag(tag)).unwrap_or(self.lines.len());
+        self.lines.splice(pos..pos, lines);
+    }
+
+    /// Files this playlist points at: URI lines plus `URI="..."` attributes. Key tags are left
+    /// out, since their URI is a key server rather than part of the package.
+    pub fn referenced_uris(&self) -> Vec<String> {
+        self.lines
+            .iter()
+            .filter(|l| !l.is_tag("EXT-X-KEY") && !l.is_tag("EXT-X-SESSION-KEY"))
+            .filter_map(|l| match l {
+                HlsLine::Uri(uri) => Some(uri.clone()),
+                tag => tag.attr("URI").map(|u| u.to_string()),
+            })
+            .collect()
+    }
+
+    /// Sum of all `#EXTINF` durations.
+    pub fn total_duration(&self) -> f64 {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                HlsLine::Raw(raw) => raw.strip_prefix("#EXTINF:"),
+                _ => None,
+            })
+            .filter_map(|v| v.split(',').next().and_then(|d| d.trim().parse::<f64>().ok()))
+            .sum()
+    }
+
+    pub fn has_raw(&self, tag: &str) -> bool {
+        self.lines.iter().any(|l| matches!(l, HlsLine::Raw(raw) if raw == tag))
+    }
+}
+
+impl std::fmt::Display for Playlist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            match line {
+                HlsLine::Tag { name, attrs } => {
+                    let list = attrs
+                        .iter()
+                        .map(|(k, v)| match v {
+                            HlsValue::Quoted(q) => format!("{}=\"{}\"", k, q),
+                            HlsValue::Plain(p) => format!("{}={}", k, p),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    writeln!(f, "#{}:{}", name, list)?;
+                }
+                HlsLine::Raw(raw) => writeln!(f, "{}", raw)?,
+                HlsLine::Uri(uri) => writeln!(f, "{}", uri)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_atomically(path: &str, content: &str) -> Result<(), String> {
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, content).map_err(|e| format!("Cannot write {}: {}", tmp_path, e))?;
+    fs::rename(&tmp_path, path).map_err(|e| format!("Cannot replace {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MPD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- written by ffmpeg -->
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" xmlns:cenc="urn:mpeg:cenc:2013" mediaPresentationDuration="PT1M2.5S">
+	<Period id="0" start="PT0.0S">
+		<AdaptationSet id="0" contentType="video" mimeType="video/mp4">
+			<ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="cenc" cenc:default_KID="0123-4567"/>
+			<Representation id="0" bandwidth="1000000" codecs="av01.0.08M.10"/>
+		</AdaptationSet>
+		<AdaptationSet id="1" contentType="audio" lang="cs">
+			<Label><![CDATA[Czech <dub> & "more"]]></Label>
+			<Representation id="1" bandwidth="128000">
+				<BaseURL>a&amp;b &#x41;&#66;</BaseURL>
+			</Representation>
+		</AdaptationSet>
+	</Period>
+</MPD>
+"#;
+
+    #[test]
+    fn mpd_parses_namespaces_cdata_and_entities() {
+        let root = parse_xml(MPD).unwrap();
+        assert_eq!(root.name, "MPD");
+        assert_eq!(root.attr("xmlns:cenc"), Some("urn:mpeg:cenc:2013"));
+        let mpd = Mpd { root };
+        assert_eq!(mpd.presentation_duration(), Some(62.5));
+
+        let sets: Vec<&XmlElement> = mpd.adaptation_sets().collect();
+        assert_eq!(sets.len(), 2);
+        let protection = sets[0].child("ContentProtection").unwrap();
+        assert_eq!(protection.attr("cenc:default_KID"), Some("0123-4567"));
+        assert_eq!(sets[1].child("Label").unwrap().text(), "Czech <dub> & \"more\"");
+        let base_url = sets[1].child("Representation").unwrap().child("BaseURL").unwrap();
+        assert_eq!(base_url.text(), "a&b AB");
+        assert_eq!(mpd.next_adaptation_set_id(), 2);
+    }
+
+    #[test]
+    fn mpd_round_trips_through_write_and_parse() {
+        let written = write_xml(&parse_xml(MPD).unwrap());
+        assert!(written.contains("<Label>Czech &lt;dub&gt; &amp; &quot;more&quot;</Label>"));
+        assert!(written.contains("cenc:default_KID=\"0123-4567\""));
+        let reparsed = parse_xml(&written).unwrap();
+        assert_eq!(write_xml(&reparsed), written);
+    }
+
+    #[test]
+    fn mpd_escapes_edited_values() {
+        let mut mpd = Mpd { root: parse_xml(MPD).unwrap() };
+        let audio = mpd.adaptation_sets_of_mut("audio").remove(0);
+        describe_adaptation_set(audio, "R&D <Commentary>", Some("en"), &["commentary"], &[]);
+        let written = write_xml(&mpd.root);
+        assert!(written.contains("<Label>R&amp;D &lt;Commentary&gt;</Label>"));
+        let audio = Mpd { root: parse_xml(&written).unwrap() }.adaptation_sets().nth(1).cloned().unwrap();
+        let order: Vec<&str> = audio.elements().map(|e| e.name.as_str()).collect();
+        assert_eq!(order, ["Label", "Role", "Representation"]);
+        assert_eq!(audio.attr("lang"), Some("en"));
+    }
+
+    #[test]
+    fn malformed_xml_is_rejected() {
+        assert!(parse_xml("<MPD><Period></MPD>").is_err());
+        assert!(parse_xml("<MPD><![CDATA[open</MPD>").is_err());
+        assert!(parse_xml("<MPD id=unquoted/>").is_err());
+    }
+
+    #[test]
+    fn iso8601_durations() {
+        assert_eq!(parse_iso8601_duration("PT1H2M3.5S"), Some(3723.5));
+        assert_eq!(parse_iso8601_duration("P1DT2H"), Some(93600.0));
+        assert_eq!(parse_iso8601_duration("PT5"), None);
+    }
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio_128k\",NAME=\"Czech, dubbed\",LANGUAGE=\"cs\",DEFAULT=YES,URI=\"media_1.m3u8\"
+#EXT-X-STREAM-INF:BANDWIDTH=1128000,RESOLUTION=1920x1080,CODECS=\"av01.0.08M.10,opus\",AUDIO=\"audio_128k\"
+media_0.m3u8
+";
+
+    #[test]
+    fn m3u8_keeps_quoted_and_plain_attributes() {
+        let playlist = Playlist::parse(MASTER);
+        let media = playlist.lines.iter().find(|l| l.is_tag("EXT-X-MEDIA")).unwrap();
+        assert_eq!(media.attr("NAME"), Some("Czech, dubbed"));
+        assert_eq!(media.attr("DEFAULT"), Some("YES"));
+        let variant = playlist.lines.iter().find(|l| l.is_tag("EXT-X-STREAM-INF")).unwrap();
+        assert_eq!(variant.attr("CODECS"), Some("av01.0.08M.10,opus"));
+        assert_eq!(variant.attr("RESOLUTION"), Some("1920x1080"));
+        assert_eq!(playlist.to_string(), MASTER);
+    }
+
+    #[test]
+    fn m3u8_edits_round_trip() {
+        let mut playlist = Playlist::parse(MASTER);
+        for variant in playlist.tags_mut("EXT-X-STREAM-INF") {
+            variant.set_quoted("SUBTITLES", "subs");
+            variant.set_plain("BANDWIDTH", "1200000");
+        }
+        let mut media = HlsLine::tag("EXT-X-MEDIA");
+        media.set_quoted("NAME", "Say \"hi\"\n");
+        playlist.insert_before_variants(vec![media]);
+        let written = playlist.to_string();
+        assert!(written.contains("BANDWIDTH=1200000,"));
+        assert!(written.contains(",SUBTITLES=\"subs\""));
+        assert!(written.contains("#EXT-X-MEDIA:NAME=\"Say 'hi' \"\n#EXT-X-STREAM-INF"));
+        assert_eq!(Playlist::parse(&written).to_string(), written);
+    }
+
+    #[test]
+    fn referenced_uris_covers_uri_lines_and_attributes() {
+        let master = Playlist::parse(MASTER);
+        assert_eq!(master.referenced_uris(), ["media_1.m3u8", "media_0.m3u8"]);
+
+        let media = Playlist::parse(
+            "#EXTM3U\n#EXT-X-TARGETDURATION:11\n#EXT-X-MAP:URI=\"init_0.mp4\"\n#EXTINF:10.500,\nchunk_0_1.m4s\n#EXTINF:2.000,\nchunk_0_2.m4s\n#EXT-X-ENDLIST\n",
+        );
+        assert_eq!(media.referenced_uris(), ["init_0.mp4", "chunk_0_1.m4s", "chunk_0_2.m4s"]);
+        assert_eq!(media.total_duration(), 12.5);
+    }
+
+    #[test]
+    fn referenced_uris_skip_key_servers() {
+        let mut master = Playlist::parse(MASTER);
+        let mut session_key = HlsLine::tag("EXT-X-SESSION-KEY");
+        session_key.set_plain("METHOD", "SAMPLE-AES-CTR");
+        session_key.set_quoted("URI", "https://keys.example.com/abc");
+        session_key.set_quoted("KEYFORMAT", "identity");
+        master.insert_before_variants(vec![session_key]);
+        let master = Playlist::parse(&master.to_string());
+        assert_eq!(master.referenced_uris(), ["media_1.m3u8", "media_0.m3u8"]);
+
+        let media = Playlist::parse(
+            "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES-CTR,URI=\"skd://abc\",KEYFORMAT=\"identity\"\n#EXT-X-MAP:URI=\"init_0.mp4\"\n#EXTINF:4.000,\nchunk_0_1.m4s\n#EXT-X-ENDLIST\n",
+        );
+        assert_eq!(media.referenced_uris(), ["init_0.mp4", "chunk_0_1.m4s"]);
+    }
+}